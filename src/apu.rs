@@ -0,0 +1,386 @@
+use crate::{
+    apu::{noise::Noise, pulse::Pulse, wave::Wave},
+    state::{Reader, Writer},
+    vgm::VgmLogger,
+};
+use alloc::string::String;
+
+mod channel;
+mod noise;
+mod pulse;
+mod wave;
+
+// Bits that always read back as 1 for NR10-NR52
+const READ_MASK: [u8; 0x17] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+pub struct Apu {
+    power: bool,
+    regs: [u8; 0x17], // NR10-NR52 as written
+    ch1: Pulse,
+    ch2: Pulse,
+    ch3: Wave,
+    ch4: Noise,
+    frame_step: u8, // The next step of the 512 Hz frame sequencer
+    div_bit: bool,  // DIV bit 4 as last seen by the frame sequencer
+    vgm: Option<VgmLogger>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            power: false,
+            regs: [0; 0x17],
+            ch1: Pulse::new(true),
+            ch2: Pulse::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            frame_step: 0,
+            div_bit: false,
+            vgm: None,
+        }
+    }
+
+    // Starts logging register writes, beginning with the current register state
+    pub fn start_vgm_log(&mut self) {
+        let mut vgm = VgmLogger::new();
+        vgm.write(0xff26, (self.power as u8) << 7);
+        for (i, &data) in self.regs[..0x16].iter().enumerate() {
+            // Don't retrigger the channels
+            let data = if i % 5 == 4 { data & 0x7f } else { data };
+            vgm.write(0xff10 + i as u16, data);
+        }
+        for addr in 0xff30..=0xff3f {
+            vgm.write(addr, self.ch3.read_ram(addr));
+        }
+        self.vgm = Some(vgm);
+    }
+
+    pub fn stop_vgm_log(&mut self) -> Option<VgmLogger> {
+        self.vgm.take()
+    }
+
+    pub fn is_vgm_logging(&self) -> bool {
+        self.vgm.is_some()
+    }
+
+    pub fn vgm_log_mut(&mut self) -> Option<&mut VgmLogger> {
+        self.vgm.as_mut()
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff26 => {
+                0x70 | ((self.power as u8) << 7)
+                    | (self.ch4.enabled() as u8) << 3
+                    | (self.ch3.enabled() as u8) << 2
+                    | (self.ch2.enabled() as u8) << 1
+                    | self.ch1.enabled() as u8
+            }
+            0xff10..=0xff25 => {
+                let i = (addr - 0xff10) as usize;
+                self.regs[i] | READ_MASK[i]
+            }
+            0xff27..=0xff2f => 0xff,
+            0xff30..=0xff3f => self.ch3.read_ram(addr),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.write(addr, data);
+        }
+
+        match addr {
+            0xff26 => {
+                let power = data & 0x80 > 0;
+                if self.power && !power {
+                    self.power_off();
+                } else if !self.power && power {
+                    self.frame_step = 0;
+                }
+                self.power = power;
+            }
+            // While powered off only the length counters can be written on DMG
+            0xff10..=0xff25 if !self.power => match addr {
+                0xff11 => self.ch1.write_length(data),
+                0xff16 => self.ch2.write_length(data),
+                0xff1b => self.ch3.write_length(data),
+                0xff20 => self.ch4.write_length(data),
+                _ => (),
+            },
+            0xff10..=0xff25 => {
+                self.regs[(addr - 0xff10) as usize] = data;
+                let odd_step = self.frame_step & 1 > 0;
+                match addr {
+                    0xff10..=0xff14 => self.ch1.write(addr - 0xff10, data, odd_step),
+                    0xff15..=0xff19 => self.ch2.write(addr - 0xff15, data, odd_step),
+                    0xff1a..=0xff1e => self.ch3.write(addr - 0xff1a, data, odd_step),
+                    0xff1f..=0xff23 => self.ch4.write(addr - 0xff1f, data, odd_step),
+                    _ => (),
+                }
+            }
+            0xff27..=0xff2f => (),
+            0xff30..=0xff3f => self.ch3.write_ram(addr, data),
+            _ => unreachable!(),
+        }
+    }
+
+    // NR10-NR51 as last written, rather than as they read back
+    pub(crate) fn written(&self, addr: u16) -> u8 {
+        self.regs[(addr - 0xff10) as usize]
+    }
+
+    // Takes 0xff10-0xff3f from a BESS state, replaying the register writes and
+    // retriggering the channels NR52 reports as playing. Their timers and
+    // envelopes aren't in the state, so they start over
+    pub(crate) fn load_bess(&mut self, regs: &[u8]) {
+        let vgm = self.vgm.take();
+        self.write(0xff26, 0x00);
+        self.write(0xff26, regs[0x16] & 0x80);
+        for (i, &data) in regs[..0x16].iter().enumerate() {
+            let data = if i % 5 == 4 { data & 0x7f } else { data };
+            self.write(0xff10 + i as u16, data);
+        }
+        for (i, &data) in regs[0x20..0x30].iter().enumerate() {
+            self.write(0xff30 + i as u16, data);
+        }
+        for ch in 0..4 {
+            if regs[0x16] & (1 << ch) > 0 {
+                let nrx4 = ch * 5 + 4;
+                self.write(0xff10 + nrx4 as u16, regs[nrx4] | 0x80);
+            }
+        }
+        self.vgm = vgm;
+    }
+
+    // A VGM log in progress keeps running across loads
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.bool(self.power);
+        w.bytes(&self.regs);
+        self.ch1.save(w);
+        self.ch2.save(w);
+        self.ch3.save(w);
+        self.ch4.save(w);
+        w.u8(self.frame_step);
+        w.bool(self.div_bit);
+    }
+
+    pub(crate) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.power = r.bool()?;
+        r.fill(&mut self.regs)?;
+        self.ch1.load(r)?;
+        self.ch2.load(r)?;
+        self.ch3.load(r)?;
+        self.ch4.load(r)?;
+        self.frame_step = r.u8()? & 7;
+        self.div_bit = r.bool()?;
+        Ok(())
+    }
+
+    fn power_off(&mut self) {
+        self.regs = [0; 0x17];
+        self.ch1.power_off();
+        self.ch2.power_off();
+        self.ch3.power_off();
+        self.ch4.power_off();
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step & 1 == 0 {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.ch1.clock_envelope();
+            self.ch2.clock_envelope();
+            self.ch4.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) & 7;
+    }
+
+    // Advances the APU by one M-cycle, `div` is the current value of the DIV register
+    pub fn emu(&mut self, div: u8) {
+        // The frame sequencer is clocked by the falling edge of DIV bit 4
+        let div_bit = div & 0x10 > 0;
+        let falling_edge = self.div_bit && !div_bit;
+        self.div_bit = div_bit;
+
+        if let Some(vgm) = &mut self.vgm {
+            vgm.emu();
+        }
+        if !self.power {
+            return;
+        }
+        if falling_edge {
+            self.step_frame_sequencer();
+        }
+
+        self.ch1.emu();
+        self.ch2.emu();
+        self.ch3.emu();
+        self.ch4.emu();
+    }
+
+    // Analog output of each channel's DAC in -1.0..=1.0, 0.0 while the DAC is off
+    fn channels(&self) -> [f32; 4] {
+        let dac = |on: bool, digital: u8| {
+            if on {
+                digital as f32 / 7.5 - 1.0
+            } else {
+                0.0
+            }
+        };
+        [
+            dac(self.ch1.dac(), self.ch1.output()),
+            dac(self.ch2.dac(), self.ch2.output()),
+            dac(self.ch3.dac(), self.ch3.output()),
+            dac(self.ch4.dac(), self.ch4.output()),
+        ]
+    }
+
+    // Pans and scales a single channel according to NR50 and NR51
+    fn pan(&self, ch: usize, out: f32) -> (f32, f32) {
+        let (nr50, nr51) = (self.regs[0x14], self.regs[0x15]);
+        let left_volume = ((nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (nr50 & 0x07) as f32 + 1.0;
+        let left = if nr51 & (0x10 << ch) > 0 { out } else { 0.0 };
+        let right = if nr51 & (0x01 << ch) > 0 { out } else { 0.0 };
+        (left * left_volume / 32.0, right * right_volume / 32.0)
+    }
+
+    // Stereo output of each channel on its own, these add up to `sample()`
+    pub fn channel_samples(&self) -> [(f32, f32); 4] {
+        let channels = self.channels();
+        [0, 1, 2, 3].map(|ch| self.pan(ch, channels[ch]))
+    }
+
+    // Mixes the channels into a stereo sample
+    pub fn sample(&self) -> (f32, f32) {
+        self.channels()
+            .into_iter()
+            .enumerate()
+            .map(|(ch, out)| self.pan(ch, out))
+            .fold((0.0, 0.0), |(l, r), (cl, cr)| (l + cl, r + cr))
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(0xff26, 0x80);
+        apu
+    }
+
+    // One falling edge of DIV bit 4
+    fn clock_frame_sequencer(apu: &mut Apu) {
+        apu.emu(0x10);
+        apu.emu(0x00);
+    }
+
+    #[test]
+    fn registers_read_back_through_the_mask() {
+        let mut apu = powered();
+        for addr in 0xff10..=0xff25 {
+            apu.write(addr, 0x00);
+            assert_eq!(apu.read(addr), READ_MASK[(addr - 0xff10) as usize]);
+            // Leave NRx4 alone so no channel gets triggered
+            if (addr - 0xff10) % 5 != 4 {
+                apu.write(addr, 0xff);
+                assert_eq!(apu.read(addr), 0xff);
+            }
+        }
+        for addr in 0xff27..=0xff2f {
+            apu.write(addr, 0x00);
+            assert_eq!(apu.read(addr), 0xff);
+        }
+        assert_eq!(apu.read(0xff26), 0xf0);
+    }
+
+    #[test]
+    fn nr52_reports_playing_channels() {
+        let mut apu = powered();
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0x80);
+        assert_eq!(apu.read(0xff26), 0xf2);
+        // Turning the DAC off stops the channel
+        apu.write(0xff17, 0x00);
+        assert_eq!(apu.read(0xff26), 0xf0);
+    }
+
+    #[test]
+    fn length_counter_stops_the_channel() {
+        let mut apu = powered();
+        // Length 62 leaves 2 clocks, which come on every other step
+        apu.write(0xff16, 0x3e);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0xc0);
+        clock_frame_sequencer(&mut apu);
+        clock_frame_sequencer(&mut apu);
+        assert_eq!(apu.read(0xff26) & 0x02, 0x02);
+        clock_frame_sequencer(&mut apu);
+        assert_eq!(apu.read(0xff26) & 0x02, 0);
+    }
+
+    #[test]
+    fn enabling_length_on_an_odd_step_clocks_it() {
+        let mut apu = powered();
+        clock_frame_sequencer(&mut apu);
+        apu.write(0xff16, 0x3f);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0x80);
+        assert_eq!(apu.read(0xff26) & 0x02, 0x02);
+        apu.write(0xff19, 0x40);
+        assert_eq!(apu.read(0xff26) & 0x02, 0);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = powered();
+        apu.write(0xff24, 0x77);
+        apu.write(0xff25, 0xff);
+        apu.write(0xff30, 0x12);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff14, 0x80);
+        assert_eq!(apu.read(0xff26), 0xf1);
+
+        apu.write(0xff26, 0x00);
+        assert_eq!(apu.read(0xff26), 0x70);
+        for addr in 0xff10..=0xff25 {
+            assert_eq!(apu.read(addr), READ_MASK[(addr - 0xff10) as usize]);
+        }
+        // Writes are ignored while off, except wave RAM
+        apu.write(0xff24, 0x77);
+        assert_eq!(apu.read(0xff24), 0x00);
+        assert_eq!(apu.read(0xff30), 0x12);
+
+        // The length counters can still be written, and keep their value
+        apu.write(0xff11, 0x3f);
+        apu.write(0xff26, 0x80);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff14, 0xc0);
+        assert_eq!(apu.read(0xff26), 0xf1);
+        clock_frame_sequencer(&mut apu);
+        assert_eq!(apu.read(0xff26), 0xf0);
+    }
+}
@@ -0,0 +1,175 @@
+// Building blocks shared by the sound channels
+use crate::state::{Reader, Writer};
+use alloc::string::String;
+
+#[derive(Clone, Default)]
+pub(super) struct Length {
+    counter: u16,
+    enabled: bool,
+    max: u16,
+}
+
+impl Length {
+    pub(super) fn new(max: u16) -> Self {
+        Self {
+            max,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn write(&mut self, data: u8) {
+        self.counter = self.max - (data as u16 & (self.max - 1));
+    }
+
+    pub(super) fn power_off(&mut self) {
+        self.enabled = false;
+    }
+
+    // Returns true if the channel has to be disabled
+    pub(super) fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    // Handles the length bits of NRx4, `odd_step` is true if the next frame
+    // sequencer step doesn't clock the length counters.
+    // Returns true if the channel has to be disabled
+    pub(super) fn write_control(&mut self, data: u8, odd_step: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = data & 0x40 > 0;
+        let trigger = data & 0x80 > 0;
+
+        let mut disable = false;
+        // Enabling the length counter in the first half of a length period clocks it once
+        if odd_step && !was_enabled && self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            disable = self.counter == 0 && !trigger;
+        }
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && odd_step {
+                self.counter -= 1;
+            }
+        }
+        disable
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        w.u16(self.counter);
+        w.bool(self.enabled);
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.counter = r.u16()?.min(self.max);
+        self.enabled = r.bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub(super) struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub(super) fn write(&mut self, data: u8) {
+        self.initial = data >> 4;
+        self.increase = data & 0x08 > 0;
+        self.period = data & 0x07;
+    }
+
+    // The DAC is powered as long as the upper 5 bits of NRx2 are not all 0
+    pub(super) fn dac(&self) -> bool {
+        self.initial > 0 || self.increase
+    }
+
+    pub(super) fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub(super) fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    pub(super) fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        w.u8(self.initial);
+        w.bool(self.increase);
+        w.u8(self.period);
+        w.u8(self.volume);
+        w.u8(self.timer);
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.initial = r.u8()? & 0x0f;
+        self.increase = r.bool()?;
+        self.period = r.u8()? & 0x07;
+        self.volume = r.u8()? & 0x0f;
+        self.timer = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_counts_down_to_zero_once_enabled() {
+        let mut length = Length::new(64);
+        length.write(62);
+        assert!(!length.clock());
+        assert!(!length.write_control(0x40, false));
+        assert!(!length.clock());
+        assert!(length.clock());
+        // Stays stopped at zero
+        assert!(!length.clock());
+    }
+
+    #[test]
+    fn trigger_reloads_an_expired_length() {
+        let mut length = Length::new(256);
+        length.write_control(0xc0, false);
+        assert_eq!(length.counter, 256);
+        // On an odd step the reload is clocked right away
+        length.counter = 0;
+        length.write_control(0xc0, true);
+        assert_eq!(length.counter, 255);
+    }
+
+    #[test]
+    fn extra_clock_on_an_odd_step() {
+        let mut length = Length::new(64);
+        length.write(63);
+        assert!(length.write_control(0x40, true));
+        // Not if the write also triggers
+        let mut length = Length::new(64);
+        length.write(63);
+        assert!(!length.write_control(0xc0, true));
+        assert_eq!(length.counter, 63);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{
+    apu::channel::{Envelope, Length},
+    state::{Reader, Writer},
+};
+use alloc::string::String;
+
+const DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Clone)]
+pub(super) struct Noise {
+    shift: u8,
+    narrow: bool, // 7-bit LFSR mode
+    divisor: u8,
+    lfsr: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+    enabled: bool,
+}
+
+impl Noise {
+    pub(super) fn new() -> Self {
+        Self {
+            shift: 0,
+            narrow: false,
+            divisor: 0,
+            lfsr: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            enabled: false,
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac(&self) -> bool {
+        self.envelope.dac()
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub(super) fn write_length(&mut self, data: u8) {
+        self.length.write(data);
+    }
+
+    pub(super) fn write(&mut self, reg: u16, data: u8, odd_step: bool) {
+        match reg {
+            0 => {}
+            1 => self.length.write(data),
+            2 => {
+                self.envelope.write(data);
+                if !self.dac() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = data >> 4;
+                self.narrow = data & 0x08 > 0;
+                self.divisor = data & 0x07;
+            }
+            4 => {
+                if self.length.write_control(data, odd_step) {
+                    self.enabled = false;
+                }
+                if data & 0x80 > 0 {
+                    self.enabled = self.dac();
+                    self.timer = self.period();
+                    self.lfsr = 0x7fff;
+                    self.envelope.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn period(&self) -> i32 {
+        DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    // Advances the channel by one M-cycle
+    pub(super) fn emu(&mut self) {
+        self.timer -= 4;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            // Shift amounts of 14 and 15 stop the LFSR
+            if self.shift >= 14 {
+                continue;
+            }
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.narrow {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+    }
+
+    // Clears everything but the length counter, which keeps running on DMG
+    pub(super) fn power_off(&mut self) {
+        let mut length = self.length.clone();
+        length.power_off();
+        *self = Self {
+            length,
+            ..Self::new()
+        };
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        w.u8(self.shift);
+        w.bool(self.narrow);
+        w.u8(self.divisor);
+        w.u16(self.lfsr);
+        w.i32(self.timer);
+        self.length.save(w);
+        self.envelope.save(w);
+        w.bool(self.enabled);
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.shift = r.u8()? & 0x0f;
+        self.narrow = r.bool()?;
+        self.divisor = r.u8()? & 0x07;
+        self.lfsr = r.u16()? & 0x7fff;
+        self.timer = r.i32()?;
+        self.length.load(r)?;
+        self.envelope.load(r)?;
+        self.enabled = r.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use crate::{
+    apu::channel::{Envelope, Length},
+    state::{Reader, Writer},
+};
+use alloc::string::String;
+
+const DUTY: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+
+#[derive(Clone, Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow: u16,
+    negated: bool, // A subtraction was calculated since the last trigger
+}
+
+#[derive(Clone)]
+pub(super) struct Pulse {
+    sweep: Option<Sweep>, // Only channel 1 has a frequency sweep unit
+    duty: u8,
+    duty_pos: u8,
+    freq: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+    enabled: bool,
+}
+
+impl Pulse {
+    pub(super) fn new(sweep: bool) -> Self {
+        Self {
+            sweep: sweep.then(Sweep::default),
+            duty: 0,
+            duty_pos: 0,
+            freq: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            enabled: false,
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac(&self) -> bool {
+        self.envelope.dac()
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if self.enabled && (DUTY[self.duty as usize] >> self.duty_pos) & 1 > 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub(super) fn write_length(&mut self, data: u8) {
+        self.length.write(data);
+    }
+
+    pub(super) fn write(&mut self, reg: u16, data: u8, odd_step: bool) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.period = (data >> 4) & 0x07;
+                    sweep.negate = data & 0x08 > 0;
+                    sweep.shift = data & 0x07;
+                    // Leaving subtraction mode after it has been used disables the channel
+                    if !sweep.negate && sweep.negated {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = data >> 6;
+                self.length.write(data);
+            }
+            2 => {
+                self.envelope.write(data);
+                if !self.dac() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.freq = (self.freq & 0x700) | data as u16,
+            4 => {
+                self.freq = (self.freq & 0xff) | ((data as u16 & 0x07) << 8);
+                if self.length.write_control(data, odd_step) {
+                    self.enabled = false;
+                }
+                if data & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.freq;
+            sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+            sweep.enabled = sweep.period > 0 || sweep.shift > 0;
+            sweep.negated = false;
+            if sweep.shift > 0 && self.calc_sweep() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn calc_sweep(&mut self) -> u16 {
+        let Some(sweep) = &mut self.sweep else {
+            return self.freq;
+        };
+        let delta = sweep.shadow >> sweep.shift;
+        if sweep.negate {
+            sweep.negated = true;
+            sweep.shadow - delta
+        } else {
+            sweep.shadow + delta
+        }
+    }
+
+    pub(super) fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer > 0 {
+            return;
+        }
+
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        let freq = self.calc_sweep();
+        if freq > 2047 {
+            self.enabled = false;
+            return;
+        }
+        if let Some(sweep) = &mut self.sweep {
+            if sweep.shift > 0 {
+                sweep.shadow = freq;
+                self.freq = freq;
+                // The new frequency is checked for overflow once more, but not written back
+                if self.calc_sweep() > 2047 {
+                    self.enabled = false;
+                }
+            }
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.freq as i32) * 4
+    }
+
+    // Advances the channel by one M-cycle
+    pub(super) fn emu(&mut self) {
+        self.timer -= 4;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_pos = (self.duty_pos + 1) & 7;
+        }
+    }
+
+    // Clears everything but the length counter, which keeps running on DMG
+    pub(super) fn power_off(&mut self) {
+        let mut length = self.length.clone();
+        length.power_off();
+        *self = Self {
+            length,
+            ..Self::new(self.sweep.is_some())
+        };
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        if let Some(sweep) = &self.sweep {
+            w.u8(sweep.period);
+            w.bool(sweep.negate);
+            w.u8(sweep.shift);
+            w.u8(sweep.timer);
+            w.bool(sweep.enabled);
+            w.u16(sweep.shadow);
+            w.bool(sweep.negated);
+        }
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.freq);
+        w.i32(self.timer);
+        self.length.save(w);
+        self.envelope.save(w);
+        w.bool(self.enabled);
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        if let Some(sweep) = &mut self.sweep {
+            sweep.period = r.u8()? & 0x07;
+            sweep.negate = r.bool()?;
+            sweep.shift = r.u8()? & 0x07;
+            sweep.timer = r.u8()?;
+            sweep.enabled = r.bool()?;
+            sweep.shadow = r.u16()? & 0x7ff;
+            sweep.negated = r.bool()?;
+        }
+        self.duty = r.u8()? & 0x03;
+        self.duty_pos = r.u8()? & 0x07;
+        self.freq = r.u16()? & 0x7ff;
+        self.timer = r.i32()?;
+        self.length.load(r)?;
+        self.envelope.load(r)?;
+        self.enabled = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Triggers channel 1 at `freq` with the sweep set up from NR10
+    fn triggered(nr10: u8, freq: u16) -> Pulse {
+        let mut pulse = Pulse::new(true);
+        pulse.write(0, nr10, false);
+        pulse.write(2, 0xf0, false);
+        pulse.write(3, freq as u8, false);
+        pulse.write(4, 0x80 | (freq >> 8) as u8, false);
+        pulse
+    }
+
+    #[test]
+    fn overflow_on_trigger_disables() {
+        // 1536 + 768 is past 2047
+        assert!(!triggered(0x11, 0x600).enabled());
+        // Without a shift nothing is calculated on trigger
+        assert!(triggered(0x10, 0x7ff).enabled());
+    }
+
+    #[test]
+    fn overflow_after_a_sweep_disables() {
+        let mut pulse = triggered(0x11, 0x500);
+        assert!(pulse.enabled());
+        // 1280 + 640 is written back, then 1920 + 960 overflows
+        pulse.clock_sweep();
+        assert_eq!(pulse.freq, 1920);
+        assert!(!pulse.enabled());
+    }
+
+    #[test]
+    fn leaving_negate_mode_disables() {
+        let mut pulse = triggered(0x19, 0x400);
+        assert!(pulse.enabled());
+        pulse.write(0, 0x11, false);
+        assert!(!pulse.enabled());
+
+        // Unless no subtraction was calculated since the trigger
+        let mut pulse = triggered(0x08, 0x400);
+        pulse.write(0, 0x00, false);
+        assert!(pulse.enabled());
+    }
+}
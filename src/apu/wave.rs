@@ -0,0 +1,148 @@
+use crate::{
+    apu::channel::Length,
+    state::{Reader, Writer},
+};
+use alloc::string::String;
+
+#[derive(Clone)]
+pub(super) struct Wave {
+    dac: bool,
+    volume: u8,
+    freq: u16,
+    timer: i32,
+    pos: u8,
+    sample: u8,
+    length: Length,
+    enabled: bool,
+    ram: [u8; 0x10],
+}
+
+impl Wave {
+    pub(super) fn new() -> Self {
+        Self {
+            dac: false,
+            volume: 0,
+            freq: 0,
+            timer: 0,
+            pos: 0,
+            sample: 0,
+            length: Length::new(256),
+            enabled: false,
+            ram: [0; 0x10],
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac(&self) -> bool {
+        self.dac
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        match self.volume {
+            _ if !self.enabled => 0,
+            0 => 0,
+            v => self.sample >> (v - 1),
+        }
+    }
+
+    pub(super) fn read_ram(&self, addr: u16) -> u8 {
+        self.ram[addr as usize & 0x0f]
+    }
+
+    pub(super) fn write_ram(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize & 0x0f] = data;
+    }
+
+    pub(super) fn write_length(&mut self, data: u8) {
+        self.length.write(data);
+    }
+
+    pub(super) fn write(&mut self, reg: u16, data: u8, odd_step: bool) {
+        match reg {
+            0 => {
+                self.dac = data & 0x80 > 0;
+                if !self.dac {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.write(data),
+            2 => self.volume = (data >> 5) & 0x03,
+            3 => self.freq = (self.freq & 0x700) | data as u16,
+            4 => {
+                self.freq = (self.freq & 0xff) | ((data as u16 & 0x07) << 8);
+                if self.length.write_control(data, odd_step) {
+                    self.enabled = false;
+                }
+                if data & 0x80 > 0 {
+                    self.enabled = self.dac;
+                    self.timer = self.period() + 6; // Extra delay before the first sample
+                    self.pos = 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.freq as i32) * 2
+    }
+
+    // Advances the channel by one M-cycle
+    pub(super) fn emu(&mut self) {
+        self.timer -= 4;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.pos = (self.pos + 1) & 0x1f;
+            let byte = self.ram[self.pos as usize >> 1];
+            self.sample = if self.pos & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0f
+            };
+        }
+    }
+
+    // Clears everything but the length counter and wave RAM
+    pub(super) fn power_off(&mut self) {
+        let mut length = self.length.clone();
+        length.power_off();
+        *self = Self {
+            length,
+            ram: self.ram,
+            ..Self::new()
+        };
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        w.bool(self.dac);
+        w.u8(self.volume);
+        w.u16(self.freq);
+        w.i32(self.timer);
+        w.u8(self.pos);
+        w.u8(self.sample);
+        self.length.save(w);
+        w.bool(self.enabled);
+        w.bytes(&self.ram);
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.dac = r.bool()?;
+        self.volume = r.u8()? & 0x03;
+        self.freq = r.u16()? & 0x7ff;
+        self.timer = r.i32()?;
+        self.pos = r.u8()? & 0x1f;
+        self.sample = r.u8()? & 0x0f;
+        self.length.load(r)?;
+        self.enabled = r.bool()?;
+        r.fill(&mut self.ram)
+    }
+}
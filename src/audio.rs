@@ -0,0 +1,78 @@
+use crate::constants::AUDIO_RATE;
+use sdl2::{
+    audio::{AudioQueue, AudioSpecDesired},
+    Sdl,
+};
+
+// Maximum deviation from the nominal resampling ratio used by dynamic rate control
+const MAX_RATE_DELTA: f64 = 0.005;
+
+pub struct Audio {
+    queue: AudioQueue<f32>,
+    target: u32, // Target buffered sample frames
+}
+
+impl Audio {
+    pub fn new(sdl: &Sdl, latency_ms: u32) -> Result<Self, String> {
+        let spec = AudioSpecDesired {
+            freq: Some(AUDIO_RATE as i32),
+            channels: Some(2),
+            samples: Some(512),
+        };
+        let queue = sdl.audio()?.open_queue::<f32, _>(None, &spec)?;
+        queue.resume();
+
+        let mut audio = Self { queue, target: 0 };
+        audio.set_latency(latency_ms);
+        Ok(audio)
+    }
+
+    pub fn set_latency(&mut self, latency_ms: u32) {
+        self.target = AUDIO_RATE * latency_ms / 1000;
+    }
+
+    pub fn queue(&mut self, samples: &[f32]) {
+        // Drop samples rather than letting the latency grow without bound
+        if self.queued() < self.target * 2 {
+            // A failed write only costs these samples
+            if let Err(e) = self.queue.queue_audio(samples) {
+                eprintln!("failed to queue audio: {}", e);
+            }
+        }
+    }
+
+    // Buffered sample frames not yet played
+    pub fn queued(&self) -> u32 {
+        self.queue.size() / (2 * std::mem::size_of::<f32>() as u32)
+    }
+
+    pub fn below_target(&self) -> bool {
+        self.queued() < self.target
+    }
+
+    // Resampling ratio that steers the buffer towards the target latency
+    pub fn rate_ratio(&self) -> f64 {
+        rate_ratio(self.queued(), self.target)
+    }
+}
+
+fn rate_ratio(queued: u32, target: u32) -> f64 {
+    let fill = queued as f64 / target.max(1) as f64;
+    1.0 + MAX_RATE_DELTA * (1.0 - fill).clamp(-1.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_ratio_follows_the_buffer_fill() {
+        assert_eq!(rate_ratio(1000, 1000), 1.0);
+        assert_eq!(rate_ratio(500, 1000), 1.0 + MAX_RATE_DELTA / 2.0);
+        assert_eq!(rate_ratio(0, 1000), 1.0 + MAX_RATE_DELTA);
+        assert_eq!(rate_ratio(2000, 1000), 1.0 - MAX_RATE_DELTA);
+        // Clamped however far off the buffer is
+        assert_eq!(rate_ratio(100_000, 1000), 1.0 - MAX_RATE_DELTA);
+        assert_eq!(rate_ratio(0, 0), 1.0 + MAX_RATE_DELTA);
+    }
+}
@@ -0,0 +1,362 @@
+// BESS (Best Effort Save State), the format SameBoy, Gambatte-based and other
+// emulators use to exchange save states
+//
+// A BESS file starts with emulator specific data, here a complete gemu state,
+// followed by the memory areas the CORE block points into and the blocks
+// themselves: a 4-byte ID, the length as a 32-bit little-endian integer and
+// the contents, up to an END block. The file ends with the offset of the
+// first block and "BESS".
+use crate::{cpu::Cpu, gameboy::Model, mem::Memory};
+use alloc::{format, string::String, vec::Vec};
+
+const FOOTER: &[u8; 4] = b"BESS";
+const NAME: &str = concat!("gemu ", env!("CARGO_PKG_VERSION"));
+const CORE_LEN: usize = 0xd0;
+// Where the (size, offset) pairs of WRAM, VRAM, cartridge RAM, OAM, HRAM and
+// the CGB palettes start in the CORE block
+const CORE_AREAS: usize = 0x98;
+
+pub fn is_bess(data: &[u8]) -> bool {
+    data.len() >= 8 && data.ends_with(FOOTER)
+}
+
+// Appends the BESS blocks to gemu's own state in `out`. BESS has no room for
+// a partly run instruction, so mid-instruction the registers are written as
+// they are and other emulators resume from there
+pub(crate) fn write(out: &mut Vec<u8>, cpu: &Cpu, mem: &Memory, model: Model, timestamp: u64) {
+    let cartridge_ram = mem.cartridge.as_ref().map_or(&[][..], |c| c.ram());
+    let areas = [
+        mem.wram.bytes(),
+        mem.ppu.vram(),
+        cartridge_ram,
+        mem.ppu.oam(),
+        &mem.hram.bytes()[..0x7f],
+    ];
+    let mut pointers = Vec::new();
+    for area in areas {
+        pointers.push((area.len() as u32, out.len() as u32));
+        out.extend_from_slice(area);
+    }
+    // No CGB palettes
+    pointers.extend([(0, 0), (0, 0)]);
+
+    let first_block = out.len() as u32;
+    block(out, b"NAME", NAME.as_bytes());
+    if let Some(cartridge) = &mem.cartridge {
+        let rom = cartridge.rom();
+        let mut info = Vec::new();
+        info.extend_from_slice(&rom[0x134..=0x143]);
+        info.extend_from_slice(&rom[0x14e..=0x14f]);
+        block(out, b"INFO", &info);
+    }
+
+    let mut core = Vec::with_capacity(CORE_LEN);
+    put_u16(&mut core, 1);
+    put_u16(&mut core, 1);
+    core.extend_from_slice(match model {
+        Model::Dmg => b"GD  ",
+        Model::Mgb => b"GM  ",
+    });
+    let r = &cpu.regs;
+    // PC is past the opcode fetched for the next instruction
+    for v in [r.pc.wrapping_sub(1), r.af(), r.bc(), r.de(), r.hl(), r.sp] {
+        put_u16(&mut core, v);
+    }
+    core.extend_from_slice(&[
+        cpu.ime() as u8,
+        mem.interrupts.read(0xffff),
+        cpu.halted() as u8,
+        0,
+    ]);
+    core.extend_from_slice(&io_registers(mem));
+    for (size, offset) in pointers {
+        put_u32(&mut core, size);
+        put_u32(&mut core, offset);
+    }
+    block(out, b"CORE", &core);
+
+    if let Some(cartridge) = &mem.cartridge {
+        let writes = cartridge.mbc_writes();
+        if !writes.is_empty() {
+            let mut mbc = Vec::new();
+            for (addr, data) in writes {
+                put_u16(&mut mbc, addr);
+                mbc.push(data);
+            }
+            block(out, b"MBC ", &mbc);
+        }
+        if let Some(rtc) = cartridge.save_rtc_bess(timestamp) {
+            block(out, b"RTC ", &rtc);
+        }
+    }
+    block(out, b"END ", &[]);
+
+    put_u32(out, first_block);
+    out.extend_from_slice(FOOTER);
+}
+
+// 0xff00-0xff7f as the CPU reads them, except the sound registers as written
+fn io_registers(mem: &Memory) -> [u8; 0x80] {
+    let mut io = [0xff; 0x80];
+    for (addr, v) in (0xff00..).zip(io.iter_mut()) {
+        *v = match addr {
+            0xff10..=0xff25 => mem.apu.written(addr),
+            0xff50 if mem.bootrom_active() => 0x00,
+            _ => mem.read(addr),
+        };
+    }
+    io
+}
+
+// Loads the BESS blocks of a state, ignoring anything before them. Whatever
+// the blocks don't cover, like the progress of the PPU through the line,
+// starts over
+pub(crate) fn read(data: &[u8], cpu: &mut Cpu, mem: &mut Memory) -> Result<(), String> {
+    let blocks = blocks(data)?;
+    let find = |id: &[u8; 4]| blocks.iter().find(|(i, _)| i == id).map(|&(_, b)| b);
+
+    if let (Some(info), Some(cartridge)) = (find(b"INFO"), &mem.cartridge) {
+        if info.len() != 0x12 {
+            return Err(format!("invalid INFO block length {}", info.len()));
+        }
+        let rom = cartridge.rom();
+        if info[..0x10] != rom[0x134..=0x143] || info[0x10..] != rom[0x14e..=0x14f] {
+            let title = String::from_utf8_lossy(&info[..0x10]);
+            let title = title.trim_end_matches('\0').trim();
+            return Err(format!(
+                "the save state was made with a different ROM ({})",
+                if title.is_empty() { "untitled" } else { title }
+            ));
+        }
+    }
+
+    let core = find(b"CORE").ok_or("the save state has no CORE block")?;
+    if core.len() < CORE_LEN {
+        return Err(format!("invalid CORE block length {}", core.len()));
+    }
+    let (major, minor) = (u16_at(core, 0x00), u16_at(core, 0x02));
+    if major != 1 {
+        return Err(format!("unsupported BESS version {}.{}", major, minor));
+    }
+    if core[0x04] != b'G' {
+        return Err(format!(
+            "save states for model {} can't be loaded, only DMG and MGB ones",
+            String::from_utf8_lossy(&core[0x04..0x08]).trim()
+        ));
+    }
+    let mut areas = [&[][..]; 5];
+    for (i, area) in areas.iter_mut().enumerate() {
+        let size = u32_at(core, CORE_AREAS + i * 8) as usize;
+        let offset = u32_at(core, CORE_AREAS + i * 8 + 4) as usize;
+        *area = slice(data, offset, size)?;
+    }
+    let [wram, vram, cartridge_ram, oam, hram] = areas;
+
+    let io = &core[0x18..0x98];
+    mem.set_bootrom_active(io[0x50] == 0)?;
+
+    let regs = &mut cpu.regs;
+    regs.pc = u16_at(core, 0x08);
+    regs.set_af(u16_at(core, 0x0a) & 0xfff0);
+    regs.set_bc(u16_at(core, 0x0c));
+    regs.set_de(u16_at(core, 0x0e));
+    regs.set_hl(u16_at(core, 0x10));
+    regs.sp = u16_at(core, 0x12);
+    // A stopped CPU carries on running
+    cpu.load_bess(core[0x14] != 0, core[0x16] == 1);
+
+    // Areas of a different size, like CGB WRAM, are copied as far as they fit
+    copy(mem.wram.bytes_mut(), wram);
+    copy(mem.ppu.vram_mut(), vram);
+    copy(mem.ppu.oam_mut(), oam);
+    copy(&mut mem.hram.bytes_mut()[..0x7f], hram);
+    if let Some(cartridge) = &mut mem.cartridge {
+        copy(cartridge.ram_mut(), cartridge_ram);
+    }
+
+    mem.joypad.write(0xff00, io[0x00]);
+    mem.serial.write(0xff01, io[0x01]);
+    mem.serial.write(0xff02, io[0x02]);
+    mem.timer.load_bess(io[0x04], io[0x05], io[0x06], io[0x07]);
+    mem.interrupts.write(0xff0f, io[0x0f]);
+    mem.interrupts.write(0xffff, core[0x15]);
+    mem.apu.load_bess(&io[0x10..0x40]);
+    mem.ppu.load_bess(&io[0x40..0x4c]);
+
+    if let Some(mbc) = find(b"MBC ") {
+        if mbc.len() % 3 != 0 {
+            return Err(format!("invalid MBC block length {}", mbc.len()));
+        }
+        for write in mbc.chunks_exact(3) {
+            let addr = u16_at(write, 0);
+            if !matches!(addr, 0x0000..=0x7fff | 0xa000..=0xbfff) {
+                return Err(format!("invalid MBC register write to {:#06x}", addr));
+            }
+            mem.write(addr, write[2]);
+        }
+    }
+    if let (Some(rtc), Some(cartridge)) = (find(b"RTC "), &mut mem.cartridge) {
+        cartridge.load_rtc_bess(rtc)?;
+    }
+
+    cpu.fetch(mem);
+    Ok(())
+}
+
+// ID and contents
+type Block<'a> = ([u8; 4], &'a [u8]);
+
+// The blocks before END
+fn blocks(data: &[u8]) -> Result<Vec<Block<'_>>, String> {
+    if !is_bess(data) {
+        return Err("not a BESS save state".into());
+    }
+    let mut offset = u32_at(data, data.len() - 8) as usize;
+    let data = &data[..data.len() - 8];
+    let mut blocks = Vec::new();
+    loop {
+        let header = slice(data, offset, 8)?;
+        let id = [header[0], header[1], header[2], header[3]];
+        let len = u32_at(header, 4) as usize;
+        if &id == b"END " {
+            return Ok(blocks);
+        }
+        blocks.push((id, slice(data, offset + 8, len)?));
+        offset += 8 + len;
+    }
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], String> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| "save state is truncated".into())
+}
+
+fn copy(dst: &mut [u8], src: &[u8]) {
+    let len = dst.len().min(src.len());
+    dst[..len].copy_from_slice(&src[..len]);
+}
+
+fn block(out: &mut Vec<u8>, id: &[u8; 4], contents: &[u8]) {
+    out.extend_from_slice(id);
+    put_u32(out, contents.len() as u32);
+    out.extend_from_slice(contents);
+}
+
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use alloc::vec;
+
+    // 64 KiB MBC1 cartridge with RAM, each ROM bank ends with its number
+    fn machine() -> (Cpu, Memory) {
+        let mut rom = vec![0; 0x10000];
+        rom[0x134..0x13c].copy_from_slice(b"BESSTEST");
+        rom[0x147] = 0x03;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x02;
+        for (bank, data) in rom.chunks_mut(0x4000).enumerate() {
+            data[0x3fff] = bank as u8;
+        }
+        let mut mem = Memory::new(None);
+        mem.cartridge = Some(Cartridge::new(rom.into_boxed_slice()).unwrap());
+        (Cpu::new(), mem)
+    }
+
+    fn save(cpu: &Cpu, mem: &Memory) -> Vec<u8> {
+        let mut data = Vec::new();
+        write(&mut data, cpu, mem, Model::Mgb, 0);
+        data
+    }
+
+    fn fill(area: &mut [u8]) {
+        for (i, v) in area.iter_mut().enumerate() {
+            *v = (i % 251) as u8;
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut cpu, mut mem) = machine();
+        fill(mem.wram.bytes_mut());
+        fill(mem.ppu.vram_mut());
+        fill(mem.ppu.oam_mut());
+        fill(mem.hram.bytes_mut());
+        for (addr, data) in [
+            (0x0000, 0x0a), // Cartridge RAM enable
+            (0x2000, 0x03), // ROM bank
+            (0xa123, 0x5a),
+            (0xff06, 0x80),
+            (0xff07, 0x05),
+            (0xff26, 0x80),
+            (0xff12, 0xf3),
+            (0xff40, 0x91),
+            (0xff42, 0x12),
+            (0xff43, 0x34),
+            (0xff47, 0xe4),
+            (0xffff, 0x05),
+        ] {
+            mem.write(addr, data);
+        }
+        let regs = &mut cpu.regs;
+        regs.set_af(0x12b0);
+        regs.set_bc(0x3456);
+        regs.set_de(0x789a);
+        regs.set_hl(0xbcde);
+        regs.sp = 0xdff0;
+        regs.pc = 0x0150;
+        cpu.fetch(&mem);
+
+        let data = save(&cpu, &mem);
+        assert!(is_bess(&data));
+        let (mut loaded_cpu, mut loaded_mem) = machine();
+        read(&data, &mut loaded_cpu, &mut loaded_mem).unwrap();
+        assert_eq!(save(&loaded_cpu, &loaded_mem), data);
+
+        let regs = &loaded_cpu.regs;
+        assert_eq!(
+            [regs.af(), regs.bc(), regs.de(), regs.hl(), regs.sp, regs.pc],
+            [0x12b0, 0x3456, 0x789a, 0xbcde, 0xdff0, 0x0151]
+        );
+        assert_eq!(loaded_mem.read(0x7fff), 3);
+        assert_eq!(loaded_mem.read(0xa123), 0x5a);
+        assert_eq!(loaded_mem.read(0xff43), 0x34);
+        assert_eq!(loaded_mem.read(0xc0fb), 0);
+    }
+
+    #[test]
+    fn rejects_other_roms() {
+        let (cpu, mem) = machine();
+        let data = save(&cpu, &mem);
+        let (mut other_cpu, mut other_mem) = machine();
+        other_mem.cartridge = Some({
+            let mut rom = vec![0; 0x8000];
+            rom[0x134..0x139].copy_from_slice(b"OTHER");
+            Cartridge::new(rom.into_boxed_slice()).unwrap()
+        });
+        assert_eq!(
+            read(&data, &mut other_cpu, &mut other_mem),
+            Err("the save state was made with a different ROM (BESSTEST)".into())
+        );
+    }
+}
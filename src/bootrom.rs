@@ -1,3 +1,5 @@
+use alloc::boxed::Box;
+
 pub struct Bootrom {
     rom: Box<[u8]>,
     active: bool,
@@ -12,6 +14,10 @@ impl Bootrom {
         self.active
     }
 
+    pub(crate) fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         self.rom[addr as usize]
     }
@@ -0,0 +1,298 @@
+use crate::{
+    cartridge::{mbc1::Mbc1, mbc3::Mbc3, mbc5::Mbc5},
+    state::{self, Reader, Writer},
+};
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+mod mbc1;
+mod mbc3;
+mod mbc5;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+// Cartridge header at 0x0100-0x014f
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: usize, // In bytes
+    pub ram_size: usize, // In bytes
+    pub destination: u8,
+    pub licensee: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Self, String> {
+        if rom.len() < 0x150 {
+            return Err(format!("ROM is too small ({} bytes)", rom.len()));
+        }
+
+        let title = rom[0x134..=0x143]
+            .iter()
+            .take_while(|&&c| c != 0)
+            .filter(|c| c.is_ascii_graphic() || **c == b' ')
+            .map(|&c| char::from(c))
+            .collect::<String>()
+            .trim()
+            .to_string();
+        let rom_size = match rom[0x148] {
+            code @ 0x00..=0x08 => (ROM_BANK_SIZE * 2) << code,
+            code => return Err(format!("invalid ROM size code {:#04x}", code)),
+        };
+        let ram_size = match rom[0x149] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => RAM_BANK_SIZE * 4,
+            0x04 => RAM_BANK_SIZE * 16,
+            0x05 => RAM_BANK_SIZE * 8,
+            code => return Err(format!("invalid RAM size code {:#04x}", code)),
+        };
+
+        Ok(Self {
+            title,
+            cgb_flag: rom[0x143],
+            sgb_flag: rom[0x146],
+            cartridge_type: rom[0x147],
+            rom_size,
+            ram_size,
+            destination: rom[0x14a],
+            licensee: rom[0x14b],
+            version: rom[0x14c],
+            header_checksum: rom[0x14d],
+            global_checksum: u16::from_be_bytes([rom[0x14e], rom[0x14f]]),
+        })
+    }
+
+    // Checksum over 0x0134-0x014c as computed by the boot ROM
+    pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+        rom[0x134..=0x14c]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1))
+    }
+
+    pub fn mbc_name(&self) -> &'static str {
+        match self.cartridge_type {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0f => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1a => "MBC5+RAM",
+            0x1b => "MBC5+RAM+BATTERY",
+            0x1c => "MBC5+RUMBLE",
+            0x1d => "MBC5+RUMBLE+RAM",
+            0x1e => "MBC5+RUMBLE+RAM+BATTERY",
+            _ => "UNKNOWN",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Mbc {
+    None,
+    Mbc1(Mbc1),
+    Mbc3(Mbc3),
+    Mbc5(Mbc5),
+}
+
+#[derive(Clone)]
+pub struct Cartridge {
+    header: Header,
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    mbc: Mbc,
+    hash: u32, // Of the ROM as loaded, identifies it in save states
+}
+
+impl Cartridge {
+    pub fn new(rom: Box<[u8]>) -> Result<Self, String> {
+        let header = Header::parse(&rom)?;
+        let mbc = match header.cartridge_type {
+            0x00 | 0x08 | 0x09 => Mbc::None,
+            0x01..=0x03 => Mbc::Mbc1(Mbc1::new()),
+            0x0f..=0x13 => Mbc::Mbc3(Mbc3::new(matches!(header.cartridge_type, 0x0f | 0x10))),
+            0x19..=0x1e => Mbc::Mbc5(Mbc5::new()),
+            t => {
+                return Err(format!(
+                    "unsupported cartridge type {:#04x} ({})",
+                    t,
+                    header.mbc_name()
+                ))
+            }
+        };
+
+        let hash = state::hash(&rom);
+
+        // Pad the ROM up to a whole number of banks so bank masking stays in bounds
+        let banks = rom.len().div_ceil(ROM_BANK_SIZE).next_power_of_two().max(2);
+        let mut padded = rom.into_vec();
+        padded.resize(banks * ROM_BANK_SIZE, 0xff);
+
+        Ok(Self {
+            ram: vec![0; header.ram_size].into_boxed_slice(),
+            header,
+            rom: padded.into_boxed_slice(),
+            mbc,
+            hash,
+        })
+    }
+
+    pub fn rom_hash(&self) -> u32 {
+        self.hash
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.blob(&self.ram);
+        match &self.mbc {
+            Mbc::None => (),
+            Mbc::Mbc1(mbc) => mbc.save(w),
+            Mbc::Mbc3(mbc) => mbc.save(w),
+            Mbc::Mbc5(mbc) => mbc.save(w),
+        }
+    }
+
+    pub(crate) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        r.blob_into(&mut self.ram)?;
+        match &mut self.mbc {
+            Mbc::None => Ok(()),
+            Mbc::Mbc1(mbc) => mbc.load(r),
+            Mbc::Mbc3(mbc) => mbc.load(r),
+            Mbc::Mbc5(mbc) => mbc.load(r),
+        }
+    }
+
+    pub(crate) fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub(crate) fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub(crate) fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    // Writes to 0x0000-0x7fff that restore the MBC's banking state
+    pub(crate) fn mbc_writes(&self) -> Vec<(u16, u8)> {
+        match &self.mbc {
+            Mbc::None => Vec::new(),
+            Mbc::Mbc1(mbc) => mbc.register_writes(),
+            Mbc::Mbc3(mbc) => mbc.register_writes(),
+            Mbc::Mbc5(mbc) => mbc.register_writes(),
+        }
+    }
+
+    // The MBC3 clock as a BESS RTC block, None without one
+    pub(crate) fn save_rtc_bess(&self, timestamp: u64) -> Option<Vec<u8>> {
+        let Mbc::Mbc3(mbc) = &self.mbc else {
+            return None;
+        };
+        let mut rtc = mbc.save_rtc_bess()?;
+        rtc.extend_from_slice(&timestamp.to_le_bytes());
+        Some(rtc)
+    }
+
+    // The clock keeps counting emulated time, so the timestamp isn't used to
+    // catch up with the time spent outside the emulator
+    pub(crate) fn load_rtc_bess(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != 0x30 {
+            return Err(format!("invalid RTC block length {}", data.len()));
+        }
+        if let Mbc::Mbc3(mbc) = &mut self.mbc {
+            mbc.load_rtc_bess(data);
+        }
+        Ok(())
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7fff => match &self.mbc {
+                Mbc::None => self.rom[addr as usize],
+                Mbc::Mbc1(mbc) => mbc.read_rom(&self.rom, addr),
+                Mbc::Mbc3(mbc) => mbc.read_rom(&self.rom, addr),
+                Mbc::Mbc5(mbc) => mbc.read_rom(&self.rom, addr),
+            },
+            0xa000..=0xbfff => match &self.mbc {
+                Mbc::None => ram_read(&self.ram, (addr - 0xa000) as usize),
+                Mbc::Mbc1(mbc) => mbc.read_ram(&self.ram, addr),
+                Mbc::Mbc3(mbc) => mbc.read_ram(&self.ram, addr),
+                Mbc::Mbc5(mbc) => mbc.read_ram(&self.ram, addr),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x7fff => match &mut self.mbc {
+                Mbc::None => (),
+                Mbc::Mbc1(mbc) => mbc.write(addr, data),
+                Mbc::Mbc3(mbc) => mbc.write(addr, data),
+                Mbc::Mbc5(mbc) => mbc.write(addr, data),
+            },
+            0xa000..=0xbfff => match &mut self.mbc {
+                Mbc::None => ram_write(&mut self.ram, (addr - 0xa000) as usize, data),
+                Mbc::Mbc1(mbc) => mbc.write_ram(&mut self.ram, addr, data),
+                Mbc::Mbc3(mbc) => mbc.write_ram(&mut self.ram, addr, data),
+                Mbc::Mbc5(mbc) => mbc.write_ram(&mut self.ram, addr, data),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    // Advances the cartridge by one M-cycle, only the MBC3 real time clock needs it
+    pub fn emu(&mut self) {
+        if let Mbc::Mbc3(mbc) = &mut self.mbc {
+            mbc.emu();
+        }
+    }
+}
+
+// Bank `bank` of the ROM at `addr` within the bank, wrapping around like the address lines do
+fn rom_read(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    let banks = rom.len() / ROM_BANK_SIZE;
+    rom[(bank & (banks - 1)) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1))]
+}
+
+// Unmapped cartridge RAM reads as open bus
+fn ram_read(ram: &[u8], offset: usize) -> u8 {
+    if ram.is_empty() {
+        0xff
+    } else {
+        ram[offset % ram.len()]
+    }
+}
+
+fn ram_write(ram: &mut [u8], offset: usize, data: u8) {
+    if !ram.is_empty() {
+        let len = ram.len();
+        ram[offset % len] = data;
+    }
+}
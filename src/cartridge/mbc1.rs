@@ -0,0 +1,87 @@
+use crate::{
+    cartridge::{ram_read, ram_write, rom_read, RAM_BANK_SIZE},
+    state::{Reader, Writer},
+};
+use alloc::{string::String, vec, vec::Vec};
+
+#[derive(Clone)]
+pub struct Mbc1 {
+    ram_enable: bool,
+    rom_bank: u8, // Lower 5 bits of the ROM bank
+    upper: u8,    // RAM bank or upper 2 bits of the ROM bank
+    mode: bool,   // Advanced banking mode, `upper` also applies to 0x0000-0x3fff and RAM
+}
+
+impl Mbc1 {
+    pub(super) fn new() -> Self {
+        Self {
+            ram_enable: false,
+            rom_bank: 1,
+            upper: 0,
+            mode: false,
+        }
+    }
+
+    pub(super) fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3fff if self.mode => (self.upper as usize) << 5,
+            0x0000..=0x3fff => 0,
+            _ => (self.upper as usize) << 5 | self.rom_bank as usize,
+        };
+        rom_read(rom, bank, addr)
+    }
+
+    fn ram_offset(&self, addr: u16) -> usize {
+        let bank = if self.mode { self.upper as usize } else { 0 };
+        bank * RAM_BANK_SIZE + (addr - 0xa000) as usize
+    }
+
+    pub(super) fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+        ram_read(ram, self.ram_offset(addr))
+    }
+
+    pub(super) fn write_ram(&self, ram: &mut [u8], addr: u16, data: u8) {
+        if self.ram_enable {
+            ram_write(ram, self.ram_offset(addr), data);
+        }
+    }
+
+    pub(super) fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = data & 0x0f == 0x0a,
+            // Bank 0 can't be selected here, it maps to bank 1
+            0x2000..=0x3fff => self.rom_bank = (data & 0x1f).max(1),
+            0x4000..=0x5fff => self.upper = data & 0x03,
+            0x6000..=0x7fff => self.mode = data & 0x01 > 0,
+            _ => unreachable!(),
+        }
+    }
+
+    // Writes that bring a fresh MBC1 to this banking state
+    pub(super) fn register_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, if self.ram_enable { 0x0a } else { 0x00 }),
+            (0x2000, self.rom_bank),
+            (0x4000, self.upper),
+            (0x6000, self.mode as u8),
+        ]
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        w.bool(self.ram_enable);
+        w.u8(self.rom_bank);
+        w.u8(self.upper);
+        w.bool(self.mode);
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.ram_enable = r.bool()?;
+        self.rom_bank = (r.u8()? & 0x1f).max(1);
+        self.upper = r.u8()? & 0x03;
+        self.mode = r.bool()?;
+        Ok(())
+    }
+}
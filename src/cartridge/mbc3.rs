@@ -0,0 +1,253 @@
+use crate::{
+    cartridge::{ram_read, ram_write, rom_read, RAM_BANK_SIZE},
+    constants::M_CYCLE_HZ,
+    state::{Reader, Writer},
+};
+use alloc::{string::String, vec, vec::Vec};
+
+const RTC_HALT: u8 = 1 << 6;
+const RTC_CARRY: u8 = 1 << 7;
+
+// Real time clock, counting emulated time so runs stay reproducible
+#[derive(Clone, Default)]
+struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,   // 9-bit day counter
+    flags: u8,   // Halt and day counter carry, as in the upper bits of DH
+    cycles: u32, // M-cycles into the current second
+}
+
+impl Rtc {
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0a => self.hours,
+            0x0b => self.days as u8,
+            0x0c => self.flags | 0x3e | (self.days >> 8) as u8,
+            _ => 0xff,
+        }
+    }
+
+    fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            0x08 => {
+                self.seconds = data & 0x3f;
+                // Writing the seconds resets the sub-second divider
+                self.cycles = 0;
+            }
+            0x09 => self.minutes = data & 0x3f,
+            0x0a => self.hours = data & 0x1f,
+            0x0b => self.days = (self.days & 0x100) | data as u16,
+            0x0c => {
+                self.days = (self.days & 0xff) | (data as u16 & 0x01) << 8;
+                self.flags = data & (RTC_HALT | RTC_CARRY);
+            }
+            _ => (),
+        }
+    }
+
+    fn tick(&mut self) {
+        // Out of range values keep counting until they overflow their bits
+        self.seconds = (self.seconds + 1) & 0x3f;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3f;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1f;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1ff {
+            self.days = 0;
+            self.flags |= RTC_CARRY;
+        }
+    }
+
+    fn emu(&mut self) {
+        if self.flags & RTC_HALT > 0 {
+            return;
+        }
+        self.cycles += 1;
+        if self.cycles >= M_CYCLE_HZ {
+            self.cycles = 0;
+            self.tick();
+        }
+    }
+
+    // Each register as a 32-bit little-endian value, as BESS stores them
+    fn save_bess(&self, out: &mut Vec<u8>) {
+        for reg in 0x08..=0x0c {
+            let data = if reg == 0x0c {
+                self.read(reg) & !0x3e
+            } else {
+                self.read(reg)
+            };
+            out.extend_from_slice(&(data as u32).to_le_bytes());
+        }
+    }
+
+    fn load_bess(&mut self, data: &[u8]) {
+        for (reg, value) in (0x08..=0x0c).zip(data.chunks_exact(4)) {
+            self.write(reg, value[0]);
+        }
+    }
+
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&[self.seconds, self.minutes, self.hours]);
+        w.u16(self.days);
+        w.u8(self.flags);
+        w.u32(self.cycles);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.seconds = r.u8()? & 0x3f;
+        self.minutes = r.u8()? & 0x3f;
+        self.hours = r.u8()? & 0x1f;
+        self.days = r.u16()? & 0x1ff;
+        self.flags = r.u8()? & (RTC_HALT | RTC_CARRY);
+        self.cycles = r.u32()? % M_CYCLE_HZ;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Mbc3 {
+    ram_enable: bool, // Also enables the RTC registers
+    rom_bank: u8,
+    select: u8, // RAM bank 0x00-0x03 or RTC register 0x08-0x0c
+    latch: u8,  // Last value written to the latch register
+    rtc: Option<Rtc>,
+    latched: Rtc,
+}
+
+impl Mbc3 {
+    pub(super) fn new(timer: bool) -> Self {
+        Self {
+            ram_enable: false,
+            rom_bank: 1,
+            select: 0,
+            latch: 0xff,
+            rtc: timer.then(Rtc::default),
+            latched: Rtc::default(),
+        }
+    }
+
+    pub(super) fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3fff => 0,
+            _ => self.rom_bank as usize,
+        };
+        rom_read(rom, bank, addr)
+    }
+
+    fn ram_offset(&self, addr: u16) -> usize {
+        self.select as usize * RAM_BANK_SIZE + (addr - 0xa000) as usize
+    }
+
+    pub(super) fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        match self.select {
+            _ if !self.ram_enable => 0xff,
+            0x00..=0x03 => ram_read(ram, self.ram_offset(addr)),
+            _ if self.rtc.is_some() => self.latched.read(self.select),
+            _ => 0xff,
+        }
+    }
+
+    pub(super) fn write_ram(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+        if !self.ram_enable {
+            return;
+        }
+        match self.select {
+            0x00..=0x03 => ram_write(ram, self.ram_offset(addr), data),
+            select => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write(select, data);
+                    self.latched.write(select, data);
+                }
+            }
+        }
+    }
+
+    pub(super) fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = data & 0x0f == 0x0a,
+            0x2000..=0x3fff => self.rom_bank = (data & 0x7f).max(1),
+            0x4000..=0x5fff => self.select = data & 0x0f,
+            0x6000..=0x7fff => {
+                // Writing 0 then 1 copies the running clock into the readable registers
+                if self.latch == 0x00 && data == 0x01 {
+                    if let Some(rtc) = &self.rtc {
+                        self.latched = rtc.clone();
+                    }
+                }
+                self.latch = data;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn emu(&mut self) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.emu();
+        }
+    }
+
+    // Writes that bring a fresh MBC3 to this banking state, the latch is left
+    // out since writing it could latch the clock
+    pub(super) fn register_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, if self.ram_enable { 0x0a } else { 0x00 }),
+            (0x2000, self.rom_bank),
+            (0x4000, self.select),
+        ]
+    }
+
+    // The running and latched clock registers, as in a BESS RTC block
+    pub(super) fn save_rtc_bess(&self) -> Option<Vec<u8>> {
+        let rtc = self.rtc.as_ref()?;
+        let mut out = Vec::new();
+        rtc.save_bess(&mut out);
+        self.latched.save_bess(&mut out);
+        Some(out)
+    }
+
+    pub(super) fn load_rtc_bess(&mut self, data: &[u8]) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_bess(&data[..20]);
+            self.latched.load_bess(&data[20..40]);
+        }
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        w.bool(self.ram_enable);
+        w.u8(self.rom_bank);
+        w.u8(self.select);
+        w.u8(self.latch);
+        if let Some(rtc) = &self.rtc {
+            rtc.save(w);
+            self.latched.save(w);
+        }
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.ram_enable = r.bool()?;
+        self.rom_bank = (r.u8()? & 0x7f).max(1);
+        self.select = r.u8()? & 0x0f;
+        self.latch = r.u8()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load(r)?;
+            self.latched.load(r)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use crate::{
+    cartridge::{ram_read, ram_write, rom_read, RAM_BANK_SIZE},
+    state::{Reader, Writer},
+};
+use alloc::{string::String, vec, vec::Vec};
+
+#[derive(Clone)]
+pub struct Mbc5 {
+    ram_enable: bool,
+    rom_bank: u16, // 9 bits, bank 0 can be mapped to 0x4000-0x7fff
+    ram_bank: u8,
+}
+
+impl Mbc5 {
+    pub(super) fn new() -> Self {
+        Self {
+            ram_enable: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+
+    pub(super) fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3fff => 0,
+            _ => self.rom_bank as usize,
+        };
+        rom_read(rom, bank, addr)
+    }
+
+    fn ram_offset(&self, addr: u16) -> usize {
+        self.ram_bank as usize * RAM_BANK_SIZE + (addr - 0xa000) as usize
+    }
+
+    pub(super) fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+        ram_read(ram, self.ram_offset(addr))
+    }
+
+    pub(super) fn write_ram(&self, ram: &mut [u8], addr: u16, data: u8) {
+        if self.ram_enable {
+            ram_write(ram, self.ram_offset(addr), data);
+        }
+    }
+
+    pub(super) fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = data == 0x0a,
+            0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            0x3000..=0x3fff => self.rom_bank = (self.rom_bank & 0xff) | (data as u16 & 0x01) << 8,
+            // Bit 3 drives the rumble motor on rumble cartridges
+            0x4000..=0x5fff => self.ram_bank = data & 0x0f,
+            0x6000..=0x7fff => (),
+            _ => unreachable!(),
+        }
+    }
+
+    // Writes that bring a fresh MBC5 to this banking state
+    pub(super) fn register_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, if self.ram_enable { 0x0a } else { 0x00 }),
+            (0x2000, self.rom_bank as u8),
+            (0x3000, (self.rom_bank >> 8) as u8),
+            (0x4000, self.ram_bank),
+        ]
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        w.bool(self.ram_enable);
+        w.u16(self.rom_bank);
+        w.u8(self.ram_bank);
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.ram_enable = r.bool()?;
+        self.rom_bank = r.u16()? & 0x1ff;
+        self.ram_bank = r.u8()? & 0x0f;
+        Ok(())
+    }
+}
@@ -0,0 +1,506 @@
+use crate::{
+    joypad::Button,
+    keymap::KeyBindings,
+    palette::Palette,
+    toml::{self, quote, Value},
+};
+use sdl2::{controller::Button as PadButton, keyboard::Keycode};
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+// Keys of the frontend actions, rebindable in the `[hotkeys]` table
+#[derive(Clone, Copy)]
+pub struct Hotkeys {
+    pub record: Keycode,
+    pub vgm_log: Keycode,
+    pub vgm_loop: Keycode,
+    pub palette: Keycode,
+    pub fullscreen: Keycode,
+    pub pause: Keycode,
+    pub frame_advance: Keycode,
+    pub fast_forward: Keycode, // Held
+    pub fast_forward_toggle: Keycode,
+    pub slow_motion: Keycode,
+    pub save_state: Keycode,
+    pub load_state: Keycode,
+    pub next_slot: Keycode,
+    pub previous_slot: Keycode,
+    pub rewind: Keycode, // Held
+}
+
+impl Hotkeys {
+    const NAMES: [&'static str; 15] = [
+        "record",
+        "vgm_log",
+        "vgm_loop",
+        "palette",
+        "fullscreen",
+        "pause",
+        "frame_advance",
+        "fast_forward",
+        "fast_forward_toggle",
+        "slow_motion",
+        "save_state",
+        "load_state",
+        "next_slot",
+        "previous_slot",
+        "rewind",
+    ];
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Keycode> {
+        match name {
+            "record" => Some(&mut self.record),
+            "vgm_log" => Some(&mut self.vgm_log),
+            "vgm_loop" => Some(&mut self.vgm_loop),
+            "palette" => Some(&mut self.palette),
+            "fullscreen" => Some(&mut self.fullscreen),
+            "pause" => Some(&mut self.pause),
+            "frame_advance" => Some(&mut self.frame_advance),
+            "fast_forward" => Some(&mut self.fast_forward),
+            "fast_forward_toggle" => Some(&mut self.fast_forward_toggle),
+            "slow_motion" => Some(&mut self.slow_motion),
+            "save_state" => Some(&mut self.save_state),
+            "load_state" => Some(&mut self.load_state),
+            "next_slot" => Some(&mut self.next_slot),
+            "previous_slot" => Some(&mut self.previous_slot),
+            "rewind" => Some(&mut self.rewind),
+            _ => None,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<Keycode> {
+        let mut hotkeys = *self;
+        hotkeys.get_mut(name).copied()
+    }
+
+    // The action bound to `key`, if any
+    pub fn action(&self, key: Keycode) -> Option<&'static str> {
+        Self::NAMES
+            .into_iter()
+            .find(|name| self.get(name) == Some(key))
+    }
+
+    // Another action on the same key as `name`, one of them would never run
+    fn shadowed(&self, name: &str) -> Option<(&'static str, Keycode)> {
+        let key = self.get(name)?;
+        Self::NAMES
+            .into_iter()
+            .find(|&other| other != name && self.get(other) == Some(key))
+            .map(|other| (other, key))
+    }
+
+    // A key can't be both a hotkey and a joypad button, the hotkey would win
+    pub fn check(&self, keys: &KeyBindings) -> Result<(), String> {
+        for name in Self::NAMES {
+            let Some(key) = self.get(name) else {
+                continue;
+            };
+            if let Some(button) = keys.button(key) {
+                return Err(format!(
+                    "key `{}` is bound to both the {} hotkey and the {} button",
+                    key.name(),
+                    name,
+                    button.name()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Self {
+            record: Keycode::F9,
+            vgm_log: Keycode::F10,
+            vgm_loop: Keycode::F11,
+            palette: Keycode::F8,
+            fullscreen: Keycode::F12,
+            pause: Keycode::P,
+            frame_advance: Keycode::N,
+            fast_forward: Keycode::Tab,
+            fast_forward_toggle: Keycode::Backquote,
+            slow_motion: Keycode::F7,
+            save_state: Keycode::F2,
+            load_state: Keycode::F4,
+            next_slot: Keycode::F3,
+            previous_slot: Keycode::F1,
+            rewind: Keycode::R,
+        }
+    }
+}
+
+// Frontend settings kept in `$XDG_CONFIG_HOME/gemu/config.toml`, command line
+// options take precedence over them
+#[derive(Clone, Default)]
+pub struct Config {
+    path: Option<PathBuf>, // Where the settings are written back to
+    pub scale: Option<u32>,
+    pub fullscreen: Option<bool>,
+    pub integer_scale: Option<bool>,
+    pub vsync: Option<bool>,
+    pub pause_on_focus_loss: Option<bool>,
+    pub palette: Option<String>, // A built-in palette name or a palette file
+    pub latency: Option<u32>,
+    pub fast_forward: Option<f64>, // 0 runs as fast as possible
+    pub slow_motion: Option<f64>,
+    pub rewind_interval: Option<u32>, // Frames between snapshots
+    pub rewind_speed: Option<f64>,
+    pub rewind_budget: Option<u32>, // MiB, 0 disables rewinding
+    pub deadzone: Option<i16>,
+    pub captures_dir: Option<PathBuf>, // WAV and VGM captures and printed sheets
+    pub saves_dir: Option<PathBuf>,    // Save state slots
+    pub keys: Vec<(Keycode, Button)>,
+    pub pad: Vec<(PadButton, Button)>,
+    pub hotkeys: Hotkeys,
+}
+
+impl Config {
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("gemu").join("config.toml"))
+    }
+
+    // A missing file gives the default settings, which are saved there on the first change
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut config = match fs::read_to_string(path) {
+            Ok(s) => Self::parse(&s).map_err(|e| format!("{}:{}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+        };
+        config.path = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    // Errors start with the line number and name the offending key
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut hotkeys = vec![];
+        for entry in toml::parse(s, &["paths", "keys", "pad", "hotkeys"])? {
+            let (line, name) = (entry.line, entry.name());
+            if entry.table == "hotkeys" {
+                hotkeys.push((line, name.clone(), entry.key.clone()));
+            }
+            config
+                .set(&entry.table, &entry.key, entry.value)
+                .map_err(|e| format!("{}: `{}`: {}", line, name, e))?;
+        }
+
+        // Two hotkeys on one key are only known once the whole file is read,
+        // a later line may move the other one. The last line setting either is at fault
+        for (line, name, action) in hotkeys.iter().rev() {
+            if let Some((other, key)) = config.hotkeys.shadowed(action) {
+                return Err(format!(
+                    "{}: `{}`: key `{}` is also bound to the {} hotkey",
+                    line,
+                    name,
+                    key.name(),
+                    other
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, table: &str, key: &str, value: Value) -> Result<(), String> {
+        match (table, key) {
+            ("", "scale") => self.scale = Some(value.integer(1..=16)? as u32),
+            ("", "fullscreen") => self.fullscreen = Some(value.boolean()?),
+            ("", "integer_scale") => self.integer_scale = Some(value.boolean()?),
+            ("", "vsync") => self.vsync = Some(value.boolean()?),
+            ("", "pause_on_focus_loss") => self.pause_on_focus_loss = Some(value.boolean()?),
+            ("", "palette") => {
+                let name = value.string()?;
+                Palette::find(&name)?;
+                self.palette = Some(name);
+            }
+            ("", "fast_forward") => self.fast_forward = Some(value.number(0.0..=64.0)?),
+            ("", "slow_motion") => self.slow_motion = Some(value.number(0.05..=1.0)?),
+            ("", "rewind_interval") => self.rewind_interval = Some(value.integer(1..=600)? as u32),
+            ("", "rewind_speed") => self.rewind_speed = Some(value.number(0.1..=16.0)?),
+            ("", "rewind_budget") => self.rewind_budget = Some(value.integer(0..=4096)? as u32),
+            ("", "latency") => self.latency = Some(value.integer(1..=1000)? as u32),
+            ("", "deadzone") => self.deadzone = Some(value.integer(0..=i16::MAX as i64)? as i16),
+            ("paths", "captures") => self.captures_dir = Some(value.string()?.into()),
+            ("paths", "saves") => self.saves_dir = Some(value.string()?.into()),
+            ("keys", key) => {
+                let keycode =
+                    Keycode::from_name(key).ok_or_else(|| format!("unknown key `{}`", key))?;
+                self.keys.push((keycode, button(value)?));
+            }
+            ("pad", pad_button) => {
+                let pad_button = PadButton::from_string(pad_button)
+                    .ok_or_else(|| format!("unknown controller button `{}`", pad_button))?;
+                self.pad.push((pad_button, button(value)?));
+            }
+            ("hotkeys", action) => {
+                let hotkey = self
+                    .hotkeys
+                    .get_mut(action)
+                    .ok_or_else(|| "unknown hotkey".to_string())?;
+                let key = value.string()?;
+                *hotkey =
+                    Keycode::from_name(&key).ok_or_else(|| format!("unknown key `{}`", key))?;
+            }
+            _ => return Err("unknown setting".to_string()),
+        }
+        Ok(())
+    }
+
+    pub fn captures_dir(&self) -> &Path {
+        self.captures_dir.as_deref().unwrap_or(Path::new("."))
+    }
+
+    pub fn saves_dir(&self) -> &Path {
+        self.saves_dir.as_deref().unwrap_or(Path::new("."))
+    }
+
+    // Writes the settings back to where they were loaded from, comments are not kept
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_string())
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "# gemu settings, rewritten when they change from the frontend"
+        )?;
+        if let Some(scale) = self.scale {
+            writeln!(f, "scale = {}", scale)?;
+        }
+        for (key, value) in [
+            ("fullscreen", self.fullscreen),
+            ("integer_scale", self.integer_scale),
+            ("vsync", self.vsync),
+            ("pause_on_focus_loss", self.pause_on_focus_loss),
+        ] {
+            if let Some(value) = value {
+                writeln!(f, "{} = {}", key, value)?;
+            }
+        }
+        if let Some(palette) = &self.palette {
+            writeln!(f, "palette = {}", quote(palette))?;
+        }
+        if let Some(latency) = self.latency {
+            writeln!(f, "latency = {}", latency)?;
+        }
+        if let Some(speed) = self.fast_forward {
+            writeln!(f, "fast_forward = {:?}", speed)?;
+        }
+        if let Some(speed) = self.slow_motion {
+            writeln!(f, "slow_motion = {:?}", speed)?;
+        }
+        if let Some(interval) = self.rewind_interval {
+            writeln!(f, "rewind_interval = {}", interval)?;
+        }
+        if let Some(speed) = self.rewind_speed {
+            writeln!(f, "rewind_speed = {:?}", speed)?;
+        }
+        if let Some(budget) = self.rewind_budget {
+            writeln!(f, "rewind_budget = {}", budget)?;
+        }
+        if let Some(deadzone) = self.deadzone {
+            writeln!(f, "deadzone = {}", deadzone)?;
+        }
+
+        writeln!(f, "\n[paths]")?;
+        if let Some(dir) = &self.captures_dir {
+            writeln!(f, "captures = {}", quote(&dir.to_string_lossy()))?;
+        }
+        if let Some(dir) = &self.saves_dir {
+            writeln!(f, "saves = {}", quote(&dir.to_string_lossy()))?;
+        }
+
+        writeln!(f, "\n[keys]")?;
+        for (key, button) in &self.keys {
+            writeln!(f, "{} = {}", quote(&key.name()), quote(button.name()))?;
+        }
+
+        writeln!(f, "\n[pad]")?;
+        for (pad_button, button) in &self.pad {
+            writeln!(
+                f,
+                "{} = {}",
+                quote(&pad_button.string()),
+                quote(button.name())
+            )?;
+        }
+
+        writeln!(f, "\n[hotkeys]")?;
+        for name in Hotkeys::NAMES {
+            if let Some(key) = self.hotkeys.get(name) {
+                writeln!(f, "{} = {}", name, quote(&key.name()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn button(value: Value) -> Result<Button, String> {
+    let name = value.string()?;
+    Button::from_name(&name).ok_or_else(|| format!("unknown button `{}`", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    const SETTINGS: &str = r#"# comment
+scale = 3
+fullscreen = true
+fast_forward = 2.5
+deadzone = 8_000
+palette = "green" # trailing comment
+
+[paths]
+captures = "caps # not a comment"
+saves = "a \"quoted\" dir\\x"
+
+[keys]
+"Left Shift" = "select"
+Z = "a"
+
+[pad]
+"leftshoulder" = "b"
+
+[ hotkeys ] # comment
+pause = "Space"
+"#;
+
+    // Loads `s` from a file so errors come back with the path as well
+    fn load_error(name: &str, s: &str) -> (String, String) {
+        let path = env::temp_dir().join(format!("gemu-{}-{}.toml", process::id(), name));
+        fs::write(&path, s).unwrap();
+        let result = Config::load(&path);
+        fs::remove_file(&path).unwrap();
+        match result {
+            Ok(_) => panic!("{:?} was accepted", s),
+            Err(e) => (e, path.display().to_string()),
+        }
+    }
+
+    #[test]
+    fn parses_settings() {
+        let config = Config::parse(SETTINGS).unwrap();
+        assert_eq!(config.scale, Some(3));
+        assert_eq!(config.fullscreen, Some(true));
+        assert_eq!(config.vsync, None);
+        assert_eq!(config.fast_forward, Some(2.5));
+        assert_eq!(config.deadzone, Some(8000));
+        assert_eq!(config.palette.as_deref(), Some("green"));
+        assert_eq!(config.captures_dir(), Path::new("caps # not a comment"));
+        assert_eq!(config.saves_dir(), Path::new("a \"quoted\" dir\\x"));
+        assert_eq!(
+            config.keys,
+            [(Keycode::LShift, Button::Select), (Keycode::Z, Button::A)]
+        );
+        assert_eq!(config.pad, [(PadButton::LeftShoulder, Button::B)]);
+        assert_eq!(config.hotkeys.pause, Keycode::Space);
+        assert_eq!(config.hotkeys.record, Hotkeys::default().record);
+    }
+
+    #[test]
+    fn writes_back_what_it_parsed() {
+        let written = Config::parse(SETTINGS).unwrap().to_string();
+        assert!(written.contains("captures = \"caps # not a comment\"\n"));
+        assert!(written.contains("\"Left Shift\" = \"select\"\n"));
+
+        let config = Config::parse(&written).unwrap();
+        assert_eq!(config.to_string(), written);
+        assert_eq!(config.captures_dir(), Path::new("caps # not a comment"));
+        assert_eq!(config.saves_dir(), Path::new("a \"quoted\" dir\\x"));
+        assert_eq!(config.keys[0], (Keycode::LShift, Button::Select));
+        assert_eq!(config.hotkeys.pause, Keycode::Space);
+    }
+
+    #[test]
+    fn rejects_hotkeys_bound_to_buttons() {
+        let mut keys = KeyBindings::new();
+        let mut hotkeys = Hotkeys::default();
+        assert_eq!(hotkeys.check(&keys), Ok(()));
+
+        hotkeys.rewind = Keycode::Backspace;
+        assert_eq!(
+            hotkeys.check(&keys),
+            Err("key `Backspace` is bound to both the rewind hotkey and the select button".into())
+        );
+        hotkeys.rewind = Keycode::R;
+        keys.bind(Keycode::P, Button::Start);
+        assert_eq!(
+            hotkeys.check(&keys),
+            Err("key `P` is bound to both the pause hotkey and the start button".into())
+        );
+    }
+
+    #[test]
+    fn swapped_hotkeys_are_accepted() {
+        let config = Config::parse("[hotkeys]\npause = \"F2\"\nsave_state = \"P\"\n").unwrap();
+        assert_eq!(config.hotkeys.pause, Keycode::F2);
+        assert_eq!(config.hotkeys.save_state, Keycode::P);
+    }
+
+    #[test]
+    fn reports_errors_with_path_and_line() {
+        for (name, s, error) in [
+            ("header", "[keys\n", "1: unterminated table header"),
+            (
+                "range",
+                "# comment\n\nscale = 17\n",
+                "3: `scale`: 17 is out of range (1-16)",
+            ),
+            (
+                "keycode",
+                "[keys]\nNoSuchKey = \"a\"\n",
+                "2: `keys.NoSuchKey`: unknown key `NoSuchKey`",
+            ),
+            (
+                "button",
+                "[keys]\nZ = \"turbo\"\n",
+                "2: `keys.Z`: unknown button `turbo`",
+            ),
+            (
+                "pad",
+                "[pad]\npaddle = \"a\"\n",
+                "2: `pad.paddle`: unknown controller button `paddle`",
+            ),
+            (
+                "hotkey",
+                "[hotkeys]\nquit = \"Q\"\n",
+                "2: `hotkeys.quit`: unknown hotkey",
+            ),
+            (
+                "hotkey_key",
+                "[hotkeys]\npause = \"NoSuchKey\"\n",
+                "2: `hotkeys.pause`: unknown key `NoSuchKey`",
+            ),
+            (
+                "hotkey_default",
+                "[hotkeys]\npause = \"F2\"\n",
+                "2: `hotkeys.pause`: key `F2` is also bound to the save_state hotkey",
+            ),
+            (
+                "hotkey_twice",
+                "[hotkeys]\nvgm_log = \"Q\"\n\nrecord = \"Q\"\npause = \"X\"\n",
+                "4: `hotkeys.record`: key `Q` is also bound to the vgm_log hotkey",
+            ),
+            ("setting", "volume = 3\n", "1: `volume`: unknown setting"),
+        ] {
+            let (e, path) = load_error(name, s);
+            assert_eq!(e, format!("{}:{}", path, error));
+        }
+    }
+}
@@ -1,9 +1,18 @@
 pub const PPU_ENABLE: u8 = 1 << 7;
+pub const WINDOW_TILE_MAP_SELECT: u8 = 1 << 6;
+pub const WINDOW_ENABLE: u8 = 1 << 5;
 pub const BG_WINDOW_TILE_DATA_SELECT: u8 = 1 << 4;
 pub const BG_TILE_MAP_SELECT: u8 = 1 << 3;
+pub const SPRITE_SIZE: u8 = 1 << 2;
+pub const SPRITE_ENABLE: u8 = 1 << 1;
 pub const BG_DISPLAY_ENABLE: u8 = 1 << 0;
 pub const LYC_EQ_LY: u8 = 1 << 2;
 
+pub const SPRITE_BG_PRIORITY: u8 = 1 << 7;
+pub const SPRITE_Y_FLIP: u8 = 1 << 6;
+pub const SPRITE_X_FLIP: u8 = 1 << 5;
+pub const SPRITE_PALETTE: u8 = 1 << 4;
+
 pub const LCD_WIDTH: usize = 160;
 pub const LCD_HEIGHT: usize = 144;
 pub const LCD_PIXELS: usize = LCD_WIDTH * LCD_HEIGHT;
@@ -11,3 +20,19 @@ pub const LCD_PIXELS: usize = LCD_WIDTH * LCD_HEIGHT;
 const M_CYCLE_CLOCK: u128 = 4;
 const CPU_CLOCK_HZ: u128 = 4194304;
 pub const M_CYCLE_NANOS: u128 = M_CYCLE_CLOCK * 1_000_000_000 / CPU_CLOCK_HZ;
+pub const M_CYCLE_HZ: u32 = (CPU_CLOCK_HZ / M_CYCLE_CLOCK) as u32;
+pub const FRAME_M_CYCLES: u32 = 17556;
+
+pub const AUDIO_RATE: u32 = 48000;
+
+pub const TIMER_INT: u8 = 1 << 2;
+pub const SERIAL_INT: u8 = 1 << 3;
+pub const JOYPAD_INT: u8 = 1 << 4;
+
+pub const TIMER_ENABLE: u8 = 1 << 2;
+
+pub const SERIAL_TRANSFER: u8 = 1 << 7;
+pub const SERIAL_INTERNAL_CLOCK: u8 = 1 << 0;
+
+pub const SELECT_DIRECTION: u8 = 1 << 4;
+pub const SELECT_ACTION: u8 = 1 << 5;
@@ -0,0 +1,137 @@
+use crate::joypad::{Button, Joypad};
+use sdl2::{
+    controller::{Axis, Button as PadButton, GameController},
+    event::Event,
+    GameControllerSubsystem, Sdl,
+};
+use std::collections::HashMap;
+
+pub struct Controllers {
+    subsystem: GameControllerSubsystem,
+    opened: HashMap<u32, GameController>, // Keyed by joystick instance id
+    bindings: HashMap<PadButton, Button>,
+    deadzone: i16,
+    held: HashMap<u32, Held>, // Keyed by joystick instance id
+}
+
+// Joypad buttons held by one controller, as bits by `Button`
+#[derive(Default)]
+struct Held {
+    buttons: u8,
+    stick: u8, // Directions held by the analog stick
+}
+
+impl Controllers {
+    pub fn new(sdl: &Sdl) -> Self {
+        let subsystem = sdl
+            .game_controller()
+            .expect("failed to init SDL game controller subsystem");
+
+        Self {
+            subsystem,
+            opened: HashMap::new(),
+            bindings: HashMap::from([
+                (PadButton::DPadRight, Button::Right),
+                (PadButton::DPadLeft, Button::Left),
+                (PadButton::DPadUp, Button::Up),
+                (PadButton::DPadDown, Button::Down),
+                (PadButton::B, Button::A),
+                (PadButton::A, Button::B),
+                (PadButton::Back, Button::Select),
+                (PadButton::Start, Button::Start),
+            ]),
+            deadzone: 8000,
+            held: HashMap::new(),
+        }
+    }
+
+    pub fn bind(&mut self, pad_button: PadButton, button: Button) {
+        self.bindings.insert(pad_button, button);
+    }
+
+    // Parses a binding written as `<pad button>=<button>`, e.g. `y=select`
+    pub fn parse(s: &str) -> Result<(PadButton, Button), String> {
+        let (pad_button, button) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid binding `{}`, expected <pad button>=<button>", s))?;
+        let pad_button = PadButton::from_string(pad_button)
+            .ok_or_else(|| format!("unknown controller button `{}`", pad_button))?;
+        let button =
+            Button::from_name(button).ok_or_else(|| format!("unknown button `{}`", button))?;
+        Ok((pad_button, button))
+    }
+
+    pub fn set_deadzone(&mut self, deadzone: i16) {
+        self.deadzone = deadzone;
+    }
+
+    // Returns true if the event was consumed
+    pub fn handle(&mut self, event: &Event, joypad: &mut Joypad) -> bool {
+        match *event {
+            // Controllers already attached at startup are reported as added too
+            Event::ControllerDeviceAdded { which, .. } => match self.subsystem.open(which) {
+                Ok(pad) => {
+                    eprintln!("controller connected: {}", pad.name());
+                    self.opened.insert(pad.instance_id(), pad);
+                }
+                Err(e) => eprintln!("failed to open controller {}: {}", which, e),
+            },
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(pad) = self.opened.remove(&which) {
+                    eprintln!("controller disconnected: {}", pad.name());
+                }
+                if let Some(held) = self.held.remove(&which) {
+                    self.release(joypad, held.buttons | held.stick);
+                }
+            }
+            Event::ControllerButtonDown { which, button, .. } => {
+                if let Some(&button) = self.bindings.get(&button) {
+                    self.held.entry(which).or_default().buttons |= 1 << button as u8;
+                    joypad.press(button);
+                }
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                if let Some(&button) = self.bindings.get(&button) {
+                    self.held.entry(which).or_default().buttons &= !(1 << button as u8);
+                    joypad.release(button);
+                }
+            }
+            Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => {
+                let (neg, pos) = match axis {
+                    Axis::LeftX => (Button::Left, Button::Right),
+                    Axis::LeftY => (Button::Up, Button::Down),
+                    _ => return true,
+                };
+                for (button, held) in [(neg, value < -self.deadzone), (pos, value > self.deadzone)]
+                {
+                    let bit = 1 << button as u8;
+                    let stick = &mut self.held.entry(which).or_default().stick;
+                    if held {
+                        *stick |= bit;
+                        joypad.press(button);
+                    } else if *stick & bit > 0 {
+                        *stick &= !bit;
+                        self.release(joypad, bit);
+                    }
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Releases the buttons in `mask` unless another controller still holds them
+    fn release(&self, joypad: &mut Joypad, mask: u8) {
+        let others = self
+            .held
+            .values()
+            .fold(0, |bits, held| bits | held.buttons | held.stick);
+        for button in Button::ALL {
+            if mask & !others & (1 << button as u8) > 0 {
+                joypad.release(button);
+            }
+        }
+    }
+}
@@ -1,31 +1,180 @@
-use crate::{cpu::reg::Registers, mem::Memory};
+use crate::{
+    cpu::reg::Registers,
+    mem::Memory,
+    state::{Reader, Writer},
+};
+use alloc::string::String;
 
 mod decode;
 mod instructions;
 mod operand;
 mod reg;
 
+// Nesting of instructions and the operands they read or write, like a CALL
+// reading its address one byte at a time
+const FRAMES: usize = 3;
+// The highest step any instruction or operand uses
+const LAST_STEP: u8 = 4;
+
+#[derive(Clone, Copy, Default)]
+struct Frame {
+    step: u8,
+    value8: u8,
+    value16: u16,
+}
+
 #[derive(Default)]
 struct Ctx {
     opcode: u8,
     cb: bool,
+    fetched: bool, // The last M-cycle finished an instruction and fetched the next opcode
+    dispatch: bool, // Calling an interrupt handler instead of running `opcode`
+    frames: [Frame; FRAMES],
+    depth: usize, // Frames in use by the step running now, 0 between M-cycles
 }
 
 pub struct Cpu {
     pub regs: Registers,
     ctx: Ctx,
+    ime: bool,
+    ime_pending: bool, // EI takes effect after the next instruction
+    halted: bool,
 }
 
 impl Cpu {
     pub fn new() -> Self {
         Self {
             regs: Registers::default(),
-            ctx: Ctx::default(),
+            ctx: Ctx {
+                fetched: true,
+                ..Default::default()
+            },
+            ime: false,
+            ime_pending: false,
+            halted: false,
         }
     }
 
     pub fn emu(&mut self, mem: &mut Memory) {
-        self.decode(mem);
+        // Interrupts are only taken between instructions
+        if self.ctx.fetched {
+            let pending = mem.interrupts.pending();
+            if self.halted {
+                // HALT ends once an interrupt is pending, even if IME is off
+                if pending == 0 {
+                    return;
+                }
+                self.halted = false;
+            }
+            if self.ime && pending != 0 {
+                self.ime = false;
+                self.ime_pending = false;
+                self.ctx.dispatch = true;
+            } else if self.ime_pending {
+                self.ime = true;
+                self.ime_pending = false;
+            }
+        }
+
+        self.ctx.fetched = false;
+        if self.ctx.dispatch {
+            self.dispatch(mem);
+        } else {
+            self.decode(mem);
+        }
+    }
+
+    // Between instructions, where the only in-flight state is the fetched opcode
+    pub fn at_boundary(&self) -> bool {
+        self.ctx.fetched
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        let r = &self.regs;
+        for v in [r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l] {
+            w.u8(v);
+        }
+        w.u16(r.sp);
+        w.u16(r.pc);
+        w.u8(self.ctx.opcode);
+        w.bool(self.ctx.cb);
+        w.bool(self.ctx.fetched);
+        w.bool(self.ctx.dispatch);
+        for frame in &self.ctx.frames {
+            w.u8(frame.step);
+            w.u8(frame.value8);
+            w.u16(frame.value16);
+        }
+        w.bool(self.ime);
+        w.bool(self.ime_pending);
+        w.bool(self.halted);
+    }
+
+    pub(crate) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        let regs = &mut self.regs;
+        for v in [
+            &mut regs.a,
+            &mut regs.f,
+            &mut regs.b,
+            &mut regs.c,
+            &mut regs.d,
+            &mut regs.e,
+            &mut regs.h,
+            &mut regs.l,
+        ] {
+            *v = r.u8()?;
+        }
+        regs.sp = r.u16()?;
+        regs.pc = r.u16()?;
+        self.ctx.opcode = r.u8()?;
+        self.ctx.cb = r.bool()?;
+        self.ctx.fetched = r.bool()?;
+        self.ctx.dispatch = r.bool()?;
+        for frame in &mut self.ctx.frames {
+            frame.step = r.u8()?;
+            frame.value8 = r.u8()?;
+            frame.value16 = r.u16()?;
+            if frame.step > LAST_STEP {
+                return Err("the save state has an invalid instruction step".into());
+            }
+        }
+        self.ime = r.bool()?;
+        self.ime_pending = r.bool()?;
+        self.halted = r.bool()?;
+        Ok(())
+    }
+
+    // IME and HALT as BESS stores them
+    pub(crate) fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub(crate) fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub(crate) fn load_bess(&mut self, ime: bool, halted: bool) {
+        self.ime = ime;
+        self.ime_pending = false;
+        self.halted = halted;
+        self.ctx.dispatch = false;
+        self.ctx.frames = Default::default();
+    }
+
+    fn frame(&self) -> &Frame {
+        &self.ctx.frames[self.ctx.depth - 1]
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        &mut self.ctx.frames[self.ctx.depth - 1]
+    }
+
+    fn value8(&self) -> u8 {
+        self.frame().value8
+    }
+
+    fn value16(&self) -> u16 {
+        self.frame().value16
     }
 
     pub fn fetch(&mut self, mem: &Memory) {
@@ -34,5 +183,121 @@ impl Cpu {
         self.ctx.opcode = opcode;
         self.regs.pc = pc.wrapping_add(1);
         self.ctx.cb = false;
+        self.ctx.fetched = true;
+        self.ctx.dispatch = false;
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bootrom::Bootrom, constants::TIMER_INT};
+    use alloc::vec;
+
+    // Runs the program from the boot ROM with the timer interrupt enabled
+    fn machine(program: &[u8], handler: &[u8]) -> (Cpu, Memory) {
+        let mut rom = vec![0; 0x100];
+        rom[..program.len()].copy_from_slice(program);
+        rom[0x50..0x50 + handler.len()].copy_from_slice(handler);
+        let mut mem = Memory::new(Some(Bootrom::new(rom.into_boxed_slice())));
+        mem.interrupts.write(0xffff, TIMER_INT);
+        (Cpu::new(), mem)
+    }
+
+    // Runs one instruction and returns its length in M-cycles
+    fn step(cpu: &mut Cpu, mem: &mut Memory) -> usize {
+        let mut cycles = 0;
+        loop {
+            cpu.emu(mem);
+            cycles += 1;
+            if cpu.at_boundary() {
+                return cycles;
+            }
+        }
+    }
+
+    // LD SP, $FFFE
+    const SETUP: [u8; 4] = [0x00, 0x31, 0xfe, 0xff];
+
+    #[test]
+    fn dispatches_after_the_instruction_following_ei() {
+        // EI, NOP, NOP with RETI as the handler
+        let program = [SETUP.as_slice(), &[0xfb, 0x00, 0x00]].concat();
+        let (mut cpu, mut mem) = machine(&program, &[0xd9]);
+        mem.interrupts.irq(TIMER_INT);
+        for cycles in [1, 1, 3, 1] {
+            assert_eq!(step(&mut cpu, &mut mem), cycles);
+        }
+        // The NOP after EI still runs
+        assert_eq!(step(&mut cpu, &mut mem), 1);
+        assert_eq!(cpu.regs.pc, 0x07);
+
+        assert_eq!(step(&mut cpu, &mut mem), 5);
+        assert_eq!(cpu.regs.pc, 0x51);
+        assert_eq!(cpu.regs.sp, 0xfffc);
+        assert_eq!(mem.read(0xfffc), 0x06);
+        assert_eq!(mem.interrupts.pending(), 0);
+        assert!(!cpu.ime);
+
+        assert_eq!(step(&mut cpu, &mut mem), 4);
+        assert_eq!(cpu.regs.pc, 0x07);
+        assert!(cpu.ime);
+    }
+
+    #[test]
+    fn di_masks_interrupts() {
+        // EI, DI, NOP
+        let program = [SETUP.as_slice(), &[0xfb, 0xf3, 0x00]].concat();
+        let (mut cpu, mut mem) = machine(&program, &[0xd9]);
+        mem.interrupts.irq(TIMER_INT);
+        for _ in 0..6 {
+            step(&mut cpu, &mut mem);
+        }
+        assert_eq!(cpu.regs.pc, 0x08);
+        assert_eq!(mem.interrupts.pending(), TIMER_INT);
+    }
+
+    #[test]
+    fn halt_waits_for_a_pending_interrupt() {
+        // EI, HALT, NOP with RETI as the handler
+        let program = [SETUP.as_slice(), &[0xfb, 0x76, 0x00]].concat();
+        let (mut cpu, mut mem) = machine(&program, &[0xd9]);
+        for _ in 0..4 {
+            step(&mut cpu, &mut mem);
+        }
+        for _ in 0..10 {
+            cpu.emu(&mut mem);
+        }
+        assert_eq!(cpu.regs.pc, 0x07);
+
+        mem.interrupts.irq(TIMER_INT);
+        assert_eq!(step(&mut cpu, &mut mem), 5);
+        assert_eq!(cpu.regs.pc, 0x51);
+        // Returns to the instruction after HALT
+        step(&mut cpu, &mut mem);
+        assert_eq!(cpu.regs.pc, 0x07);
+    }
+
+    #[test]
+    fn halt_without_ime_resumes_without_dispatching() {
+        // HALT, NOP
+        let program = [SETUP.as_slice(), &[0x76, 0x00]].concat();
+        let (mut cpu, mut mem) = machine(&program, &[0xd9]);
+        for _ in 0..3 {
+            step(&mut cpu, &mut mem);
+        }
+        cpu.emu(&mut mem);
+        assert_eq!(cpu.regs.pc, 0x06);
+
+        mem.interrupts.irq(TIMER_INT);
+        assert_eq!(step(&mut cpu, &mut mem), 1);
+        assert_eq!(cpu.regs.pc, 0x07);
+        assert_eq!(mem.interrupts.pending(), TIMER_INT);
     }
 }
@@ -154,6 +154,10 @@ impl Cpu {
             0xfa => self.ld(mem, Reg8::A, Direct8::D),
             0xcb => self.cb_prefixed(mem),
             0xcd => self.call(mem),
+            0x76 => self.halt(mem),
+            0xd9 => self.reti(mem),
+            0xf3 => self.di(mem),
+            0xfb => self.ei(mem),
             0xfe => self.cp(mem, Imm8),
             _ => panic!("Unknown opcode: {:02X}", self.ctx.opcode),
         }
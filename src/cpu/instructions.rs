@@ -3,23 +3,26 @@ use crate::{
     cpu::Cpu,
     mem::Memory,
 };
-use std::sync::atomic::{AtomicU16, AtomicU8, Ordering::Relaxed};
 
+// Multi-cycle instructions and operands run one M-cycle per call. Each nested
+// call gets a frame in `Ctx` for its step and the values it carries over, so
+// the progress of an instruction is part of the CPU state
 macro_rules! step {
-    ($d: expr, {$($c:tt : $e:expr,)*}) => {
-        static STEP: AtomicU8 = AtomicU8::new(0);
-        #[allow(unused)]
-        static VALUE8: AtomicU8 = AtomicU8::new(0);
-        #[allow(unused)]
-        static VALUE16: AtomicU16 = AtomicU16::new(0);
-        $(if STEP.load(Relaxed) == $c { $e })* else { return $d; }
+    ($cpu:ident, $d: expr, {$($c:tt : $e:expr,)*}) => {
+        $cpu.ctx.depth += 1;
+        #[allow(clippy::redundant_closure_call)]
+        let res = (|| {
+            $(if $cpu.frame().step == $c { $e })* else { return $d; }
+        })();
+        $cpu.ctx.depth -= 1;
+        return res;
     };
 }
 pub(crate) use step;
 
 macro_rules! go {
-    ($e:expr) => {
-        STEP.store($e, Relaxed)
+    ($cpu:ident, $e:expr) => {
+        $cpu.frame_mut().step = $e
     };
 }
 pub(crate) use go;
@@ -33,16 +36,16 @@ impl Cpu {
     where
         Self: IO8<D> + IO8<S>,
     {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read8(mem,src) {
-                VALUE8.store(v, Relaxed);
-                go!(1);
+                self.frame_mut().value8 = v;
+                go!(self, 1);
             },
-            1: if self.write8(mem, dst, VALUE8.load(Relaxed)).is_some() {
-                go!(2);
+            1: if self.write8(mem, dst, self.value8()).is_some() {
+                go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
@@ -52,16 +55,16 @@ impl Cpu {
     where
         Self: IO16<D> + IO16<S>,
     {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read16(mem, src) {
-                VALUE16.store(v, Relaxed);
-                go!(1);
+                self.frame_mut().value16 = v;
+                go!(self, 1);
             },
-            1: if self.write16(mem, dst, VALUE16.load(Relaxed)).is_some() {
-                go!(2);
+            1: if self.write16(mem, dst, self.value16()).is_some() {
+                go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
@@ -71,26 +74,26 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read8(mem, src) {
-                VALUE8.store(v & !(1 << bit), Relaxed);
-                go!(1);
+                self.frame_mut().value8 = v & !(1 << bit);
+                go!(self, 1);
             },
-            1: if self.write8(mem, src, VALUE8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(mem, src, self.value8()).is_some() {
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
     }
 
     pub fn jp(&mut self, mem: &Memory) {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read16(mem, Imm16) {
                 self.regs.pc = v;
-                return go!(1);
+                return go!(self, 1);
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
@@ -114,17 +117,17 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read8(mem, src) {
                 let res = v.wrapping_add(1);
                 self.regs.set_zf(res == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(v & 0xf == 0xf);
-                VALUE8.store(res, Relaxed);
-                go!(1);
+                self.frame_mut().value8 = res;
+                go!(self, 1);
             },
-            1: if self.write8(mem, src, VALUE8.load(Relaxed)).is_some(){
-                go!(0);
+            1: if self.write8(mem, src, self.value8()).is_some(){
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
@@ -134,16 +137,16 @@ impl Cpu {
     where
         Self: IO16<S>,
     {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read16(mem, src) {
-                VALUE16.store(v.wrapping_add(1), Relaxed);
-                go!(1);
+                self.frame_mut().value16 = v.wrapping_add(1);
+                go!(self, 1);
             },
-            1: if self.write16(mem, src, VALUE16.load(Relaxed)).is_some(){
-                return go!(2);
+            1: if self.write16(mem, src, self.value16()).is_some(){
+                return go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(mem)
             },
         });
@@ -153,17 +156,17 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read8(mem, src) {
                 let result = v.wrapping_sub(1);
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(true);
                 self.regs.set_hf(v & 0xf == 0);
-                VALUE8.store(result, Relaxed);
-                go!(1);
+                self.frame_mut().value8 = result;
+                go!(self, 1);
             },
-            1: if self.write8(mem, src, VALUE8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(mem, src, self.value8()).is_some() {
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
@@ -173,16 +176,16 @@ impl Cpu {
     where
         Self: IO16<S>,
     {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read16(mem, src) {
-                VALUE16.store(v.wrapping_sub(1), Relaxed);
-                go!(1);
+                self.frame_mut().value16 = v.wrapping_sub(1);
+                go!(self, 1);
             },
-            1: if self.write16(mem, src, VALUE16.load(Relaxed)).is_some() {
-                return go!(2);
+            1: if self.write16(mem, src, self.value16()).is_some() {
+                return go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
@@ -192,18 +195,18 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read8(mem, src) {
                 let res = (v << 1) | self.regs.cf() as u8;
                 self.regs.set_zf(res == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(false);
                 self.regs.set_cf(v & 0x80 > 0);
-                VALUE8.store(res, Relaxed);
-                go!(1);
+                self.frame_mut().value8 = res;
+                go!(self, 1);
             },
-            1: if self.write8(mem, src, VALUE8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(mem, src, self.value8()).is_some() {
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
@@ -223,63 +226,63 @@ impl Cpu {
     }
 
     pub fn push16(&mut self, mem: &mut Memory, val: u16) -> Option<()> {
-        step!(None, {
+        step!(self, None, {
             0: {
-                go!(1);
+                go!(self, 1);
                 return None;
             },
             1: {
                 let [lo, hi] = u16::to_le_bytes(val);
                 self.regs.sp = self.regs.sp.wrapping_sub(1);
                 mem.write(self.regs.sp, hi);
-                VALUE8.store(lo, Relaxed);
-                go!(2);
+                self.frame_mut().value8 = lo;
+                go!(self, 2);
                 return None;
             },
             2: {
                 self.regs.sp = self.regs.sp.wrapping_sub(1);
-                mem.write(self.regs.sp, VALUE8.load(Relaxed));
-                go!(3);
+                mem.write(self.regs.sp, self.value8());
+                go!(self, 3);
                 return None;
             },
-            3: return Some(go!(0)),
+            3: return Some(go!(self, 0)),
         });
     }
 
     pub fn push(&mut self, mem: &mut Memory, src: Reg16) {
-        step!((), {
+        step!(self, (), {
             0: {
-                VALUE16.store(self.read16(mem, src).unwrap(), Relaxed);
-                go!(1);
+                self.frame_mut().value16 = self.read16(mem, src).unwrap();
+                go!(self, 1);
             },
-            1: if self.push16(mem, VALUE16.load(Relaxed)).is_some() {
-                go!(2);
+            1: if self.push16(mem, self.value16()).is_some() {
+                go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
     }
 
     pub fn pop16(&mut self, mem: &Memory) -> Option<u16> {
-        step!(None, {
+        step!(self, None, {
             0: {
-                VALUE8.store(mem.read(self.regs.sp), Relaxed);
+                self.frame_mut().value8 = mem.read(self.regs.sp);
                 self.regs.sp = self.regs.sp.wrapping_add(1);
-                go!(1);
+                go!(self, 1);
                 return None;
             },
             1: {
                 let hi = mem.read(self.regs.sp);
                 self.regs.sp = self.regs.sp.wrapping_add(1);
-                VALUE16.store(u16::from_le_bytes([VALUE8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.frame_mut().value16 = u16::from_le_bytes([self.value8(), hi]);
+                go!(self, 2);
                 return None;
             },
             2: {
-                go!(0);
-                return Some(VALUE16.load(Relaxed));
+                go!(self, 0);
+                return Some(self.value16());
             },
         });
     }
@@ -292,13 +295,13 @@ impl Cpu {
     }
 
     pub fn jr(&mut self, mem: &Memory) {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read8(mem, Imm8) {
                 self.regs.pc = self.regs.pc.wrapping_add(v as i8 as u16);
-                return go!(1);
+                return go!(self, 1);
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
@@ -314,43 +317,99 @@ impl Cpu {
     }
 
     pub fn jr_c(&mut self, mem: &Memory, c: Cond) {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read8(mem, Imm8) {
-                go!(1);
+                go!(self, 1);
                 if self.cond(c) {
                     self.regs.pc = self.regs.pc.wrapping_add(v as i8 as u16);
                     return;
                 }
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
     }
 
     pub fn call(&mut self, mem: &mut Memory) {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.read16(mem, Imm16) {
-                VALUE16.store(v, Relaxed);
-                go!(1);
+                self.frame_mut().value16 = v;
+                go!(self, 1);
             },
             1: if self.push16(mem, self.regs.pc).is_some() {
-                self.regs.pc = VALUE16.load(Relaxed);
-                go!(0);
+                self.regs.pc = self.value16();
+                go!(self, 0);
+                self.fetch(mem);
+            },
+        });
+    }
+
+    pub fn reti(&mut self, mem: &Memory) {
+        step!(self, (), {
+            0: if let Some(v) = self.pop16(mem) {
+                self.regs.pc = v;
+                self.ime = true;
+                return go!(self, 1);
+            },
+            1: {
+                go!(self, 0);
+                self.fetch(mem);
+            },
+        });
+    }
+
+    pub fn ei(&mut self, mem: &Memory) {
+        self.ime_pending = true;
+        self.fetch(mem);
+    }
+
+    pub fn di(&mut self, mem: &Memory) {
+        self.ime = false;
+        self.ime_pending = false;
+        self.fetch(mem);
+    }
+
+    // The opcode after HALT is fetched right away and runs once an interrupt
+    // is pending. The HALT bug isn't emulated
+    pub fn halt(&mut self, mem: &Memory) {
+        self.halted = true;
+        self.fetch(mem);
+    }
+
+    // Calls the handler of the highest priority pending interrupt, dropping
+    // the opcode already fetched, in 5 M-cycles. The interrupt is picked
+    // after pushing PC, if none is pending anymore the CPU jumps to 0
+    pub fn dispatch(&mut self, mem: &mut Memory) {
+        step!(self, (), {
+            0: {
+                self.regs.pc = self.regs.pc.wrapping_sub(1);
+                return go!(self, 1);
+            },
+            1: if self.push16(mem, self.regs.pc).is_some() {
+                let pending = mem.interrupts.pending();
+                self.regs.pc = if pending == 0 {
+                    0
+                } else {
+                    let bit = pending.trailing_zeros();
+                    mem.interrupts.ack(1 << bit);
+                    0x40 + 8 * bit as u16
+                };
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
     }
 
     pub fn ret(&mut self, mem: &Memory) {
-        step!((), {
+        step!(self, (), {
             0: if let Some(v) = self.pop16(mem) {
                 self.regs.pc = v;
-                go!(1);
+                go!(self, 1);
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(mem);
             },
         });
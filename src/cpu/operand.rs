@@ -1,5 +1,3 @@
-use std::sync::atomic::{AtomicU16, AtomicU8, Ordering::Relaxed};
-
 use crate::{
     cpu::instructions::{go, step},
     cpu::Cpu,
@@ -117,16 +115,16 @@ impl IO16<Reg16> for Cpu {
 
 impl IO8<Imm8> for Cpu {
     fn read8(&mut self, mem: &Memory, _: Imm8) -> Option<u8> {
-        step!(None, {
+        step!(self, None, {
                 0: {
-                    VALUE8.store(mem.read(self.regs.pc), Relaxed);
+                    self.frame_mut().value8 = mem.read(self.regs.pc);
                     self.regs.pc = self.regs.pc.wrapping_add(1);
-                    go!(1);
+                    go!(self, 1);
                     return None;
                 },
                 1: {
-                    go!(0);
-                    return Some(VALUE8.load(Relaxed));
+                    go!(self, 0);
+                    return Some(self.value8());
                 },
             }
         );
@@ -139,18 +137,18 @@ impl IO8<Imm8> for Cpu {
 
 impl IO16<Imm16> for Cpu {
     fn read16(&mut self, mem: &Memory, _: Imm16) -> Option<u16> {
-        step!(None, {
+        step!(self, None, {
             0: if let Some(lo) = self.read8(mem, Imm8) {
-                VALUE8.store(lo, Relaxed);
-                go!(1);
+                self.frame_mut().value8 = lo;
+                go!(self, 1);
             },
             1: if let Some(hi) = self.read8(mem, Imm8) {
-                VALUE16.store(u16::from_le_bytes([VALUE8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.frame_mut().value16 = u16::from_le_bytes([self.value8(), hi]);
+                go!(self, 2);
             },
             2: {
-                go!(0);
-                return Some(VALUE16.load(Relaxed));
+                go!(self, 0);
+                return Some(self.value16());
             },
         });
     }
@@ -161,9 +159,9 @@ impl IO16<Imm16> for Cpu {
 
 impl IO8<Indirect> for Cpu {
     fn read8(&mut self, mem: &Memory, src: Indirect) -> Option<u8> {
-        step!(None, {
+        step!(self, None, {
            0: {
-               VALUE8.store(match src {
+               self.frame_mut().value8 = match src {
                    Indirect::BC => mem.read(self.regs.bc()),
                    Indirect::DE => mem.read(self.regs.de()),
                    Indirect::HL => mem.read(self.regs.hl()),
@@ -178,19 +176,19 @@ impl IO8<Indirect> for Cpu {
                        self.regs.set_hl(addr.wrapping_add(1));
                        mem.read(addr)
                    },
-               }, Relaxed);
-               go!(1);
+               };
+               go!(self, 1);
                return None;
            },
            1: {
-               go!(0);
-               return Some(VALUE8.load(Relaxed));
+               go!(self, 0);
+               return Some(self.value8());
            },
         });
     }
 
     fn write8(&mut self, mem: &mut Memory, dst: Indirect, val: u8) -> Option<()> {
-        step!(None, {
+        step!(self, None, {
             0: {
                 match dst {
                     Indirect::BC => mem.write(self.regs.bc(), val),
@@ -208,61 +206,61 @@ impl IO8<Indirect> for Cpu {
                         mem.write(addr, val);
                     },
                 }
-                go!(1);
+                go!(self, 1);
                 return None;
             },
-            1: return Some(go!(0)),
+            1: return Some(go!(self, 0)),
         });
     }
 }
 
 impl IO8<Direct8> for Cpu {
     fn read8(&mut self, mem: &Memory, src: Direct8) -> Option<u8> {
-        step!(None, {
+        step!(self, None, {
             0: if let Some(lo) = self.read8(mem, Imm8) {
-                VALUE8.store(lo, Relaxed);
-                go!(1);
+                self.frame_mut().value8 = lo;
+                go!(self, 1);
                 if let Direct8::DFF = src {
-                    VALUE16.store(0xff00 | (lo as u16), Relaxed);
-                    go!(2);
+                    self.frame_mut().value16 = 0xff00 | (lo as u16);
+                    go!(self, 2);
                 }
             },
             1: if let Some(hi) = self.read8(mem, Imm8) {
-                VALUE16.store(u16::from_le_bytes([VALUE8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.frame_mut().value16 = u16::from_le_bytes([self.value8(), hi]);
+                go!(self, 2);
             },
             2: {
-                VALUE8.store(mem.read(VALUE16.load(Relaxed)), Relaxed);
-                go!(3);
+                self.frame_mut().value8 = mem.read(self.value16());
+                go!(self, 3);
                 return None;
             },
             3: {
-                go!(0);
-                return Some(VALUE8.load(Relaxed));
+                go!(self, 0);
+                return Some(self.value8());
             },
         });
     }
 
     fn write8(&mut self, mem: &mut Memory, dst: Direct8, val: u8) -> Option<()> {
-        step!(None, {
+        step!(self, None, {
             0: if let Some(lo) = self.read8(mem, Imm8) {
-                VALUE8.store(lo, Relaxed);
-                go!(1);
+                self.frame_mut().value8 = lo;
+                go!(self, 1);
                 if let Direct8::DFF = dst {
-                    VALUE16.store(0xff00 | (lo as u16), Relaxed);
-                    go!(2);
+                    self.frame_mut().value16 = 0xff00 | (lo as u16);
+                    go!(self, 2);
                 }
             },
             1: if let Some(hi) = self.read8(mem, Imm8) {
-                VALUE16.store(u16::from_le_bytes([VALUE8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.frame_mut().value16 = u16::from_le_bytes([self.value8(), hi]);
+                go!(self, 2);
             },
             2: {
-                mem.write(VALUE16.load(Relaxed), val);
-                go!(3);
+                mem.write(self.value16(), val);
+                go!(self, 3);
                 return None;
             },
-            3: return Some(go!(0)),
+            3: return Some(go!(self, 0)),
         });
     }
 }
@@ -273,26 +271,26 @@ impl IO16<Direct16> for Cpu {
     }
 
     fn write16(&mut self, mem: &mut Memory, _: Direct16, val: u16) -> Option<()> {
-        step!(None, {
+        step!(self, None, {
             0: if let Some(lo) = self.read8(mem, Imm8) {
-                VALUE8.store(lo, Relaxed);
-                go!(1);
+                self.frame_mut().value8 = lo;
+                go!(self, 1);
             },
             1: if let Some(hi) = self.read8(mem, Imm8) {
-                VALUE16.store(u16::from_le_bytes([VALUE8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.frame_mut().value16 = u16::from_le_bytes([self.value8(), hi]);
+                go!(self, 2);
             },
             2: {
-                mem.write(VALUE16.load(Relaxed), val as u8);
-                go!(3);
+                mem.write(self.value16(), val as u8);
+                go!(self, 3);
                 return None;
             },
             3: {
-                mem.write(VALUE16.load(Relaxed).wrapping_add(1), (val >> 8) as u8);
-                go!(4);
+                mem.write(self.value16().wrapping_add(1), (val >> 8) as u8);
+                go!(self, 4);
                 return None;
             },
-            4: return Some(go!(0)),
+            4: return Some(go!(self, 0)),
         });
     }
 }
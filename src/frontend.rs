@@ -0,0 +1,565 @@
+use crate::{
+    audio::Audio,
+    config::Config,
+    constants::{FRAME_M_CYCLES, M_CYCLE_HZ},
+    controller::Controllers,
+    gameboy::Gameboy,
+    keymap::KeyBindings,
+    lcd::Lcd,
+    palette::Palette,
+    rewind::Rewind,
+};
+use sdl2::{
+    self,
+    event::{Event, WindowEvent},
+    keyboard::Keycode,
+    EventPump,
+};
+use std::{fs, path::PathBuf, thread, time};
+
+// Frames the realtime loop may fall behind before it gives up catching up
+const MAX_LAG_FRAMES: u32 = 4;
+
+// Save state slots 0-9
+const STATE_SLOTS: u8 = 10;
+
+// SDL window, input and audio around the emulated system
+pub struct Frontend {
+    gameboy: Gameboy,
+    lcd: Lcd,
+    events: EventPump,
+    keys: KeyBindings,
+    controllers: Controllers,
+    audio: Option<Audio>,
+    audio_sync: bool, // Pace emulation by the audio buffer instead of the system clock
+    speed: f64,       // Emulation speed relative to the real hardware
+    fast_forward: f64, // Speed while fast-forwarding, 0 for as fast as possible
+    slow_motion: f64,
+    fast_forward_held: bool,
+    fast_forward_locked: bool,
+    slow_motion_on: bool,
+    user_paused: bool,
+    rewind: Option<Rewind>, // None when disabled
+    rewind_speed: f64,      // Frames of history stepped back per frame shown
+    rewinding: bool,
+    rewind_progress: f64, // Frames of history owed to the next step back
+    rom_name: String,     // Names the save state files
+    slot: u8,
+    last_draw: time::Instant,
+    record_channels: bool, // Also record each channel when recording from the hotkey
+    config: Config,        // Settings written back when changed from here
+    pause_on_focus_loss: bool,
+    focused: bool,
+    quit: bool,
+}
+
+impl Frontend {
+    pub fn new(gameboy: Gameboy, scale: u32, vsync: bool) -> Self {
+        let sdl = sdl2::init().expect("failed to init SDL");
+        let lcd = Lcd::new(&sdl, scale, vsync);
+        let events = sdl.event_pump().expect("failed to get SDL event pump");
+        let controllers = Controllers::new(&sdl);
+        let audio = Audio::new(&sdl, 60)
+            .map_err(|e| eprintln!("failed to open audio device: {}", e))
+            .ok();
+
+        Self {
+            gameboy,
+            lcd,
+            events,
+            keys: KeyBindings::new(),
+            controllers,
+            audio,
+            audio_sync: false,
+            speed: 1.0,
+            fast_forward: 0.0,
+            slow_motion: 0.5,
+            fast_forward_held: false,
+            fast_forward_locked: false,
+            slow_motion_on: false,
+            user_paused: false,
+            rewind: None,
+            rewind_speed: 1.0,
+            rewinding: false,
+            rewind_progress: 0.0,
+            rom_name: "gemu".to_string(),
+            slot: 0,
+            last_draw: time::Instant::now(),
+            record_channels: false,
+            config: Config::default(),
+            pause_on_focus_loss: false,
+            focused: true,
+            quit: false,
+        }
+    }
+
+    pub fn set_record_channels(&mut self, record_channels: bool) {
+        self.record_channels = record_channels;
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    fn save_config(&self) {
+        if let Err(e) = self.config.save() {
+            eprintln!("failed to save settings: {}", e);
+        }
+    }
+
+    // Where captures named `name` are saved, creating the directory if needed
+    fn capture_path(&self, name: String) -> PathBuf {
+        let dir = self.config.captures_dir();
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("failed to create {}: {}", dir.display(), e);
+        }
+        dir.join(name)
+    }
+
+    fn toggle_recording(&mut self) {
+        if self.gameboy.stop_recording() {
+            eprintln!("recording stopped");
+            return;
+        }
+        let path = self.capture_path(format!("gemu-{}.wav", unix_time()));
+        match self.gameboy.start_recording(&path, self.record_channels) {
+            Ok(()) => eprintln!("recording audio to {}", path.display()),
+            Err(e) => eprintln!("failed to start recording: {}", e),
+        }
+    }
+
+    fn toggle_vgm_log(&mut self) {
+        let Some(vgm) = self.gameboy.stop_vgm_log() else {
+            self.gameboy.start_vgm_log();
+            eprintln!("logging APU writes to VGM");
+            return;
+        };
+        let path = self.capture_path(format!("gemu-{}.vgm", unix_time()));
+        match fs::write(&path, vgm) {
+            Ok(()) => eprintln!("saved VGM log to {}", path.display()),
+            Err(e) => eprintln!("failed to save VGM log: {}", e),
+        }
+    }
+
+    fn mark_vgm_loop(&mut self) {
+        if self.gameboy.mark_vgm_loop() {
+            eprintln!("marked VGM loop point");
+        }
+    }
+
+    pub fn set_rom_name(&mut self, name: &str) {
+        self.rom_name = name.to_string();
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.config
+            .saves_dir()
+            .join(format!("{}.state{}", self.rom_name, self.slot))
+    }
+
+    fn save_state(&mut self) {
+        let path = self.state_path();
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                eprintln!("failed to create {}: {}", dir.display(), e);
+            }
+        }
+        match fs::write(&path, self.gameboy.save_bess(unix_time())) {
+            Ok(()) => eprintln!("saved state {} to {}", self.slot, path.display()),
+            Err(e) => eprintln!("failed to save state: {}", e),
+        }
+    }
+
+    fn load_state(&mut self) {
+        let path = self.state_path();
+        let result = fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| self.gameboy.load_state(&data));
+        match result {
+            Ok(()) => {
+                self.lcd.draw(self.gameboy.frame_buffer());
+                eprintln!("loaded state {} from {}", self.slot, path.display());
+            }
+            Err(e) => eprintln!("failed to load {}: {}", path.display(), e),
+        }
+    }
+
+    fn select_slot(&mut self, slot: u8) {
+        self.slot = slot % STATE_SLOTS;
+        eprintln!("save state slot {}", self.slot);
+    }
+
+    // Switches to the next built-in palette and remembers it
+    fn cycle_palette(&mut self) {
+        let current = self.config.palette.as_deref().and_then(|name| {
+            Palette::BUILTIN
+                .iter()
+                .position(|(n, _)| n.eq_ignore_ascii_case(name))
+        });
+        let (name, palette) =
+            Palette::BUILTIN[current.map_or(0, |i| i + 1) % Palette::BUILTIN.len()];
+        self.gameboy.set_palette(palette);
+        self.config.palette = Some(name.to_string());
+        self.save_config();
+        eprintln!("palette: {}", name);
+    }
+
+    // Runs the action bound to `key`, returns false if it is not a hotkey
+    fn hotkey(&mut self, key: Keycode) -> bool {
+        let hotkeys = self.config.hotkeys;
+        match key {
+            k if k == hotkeys.record => self.toggle_recording(),
+            k if k == hotkeys.vgm_log => self.toggle_vgm_log(),
+            k if k == hotkeys.vgm_loop => self.mark_vgm_loop(),
+            k if k == hotkeys.palette => self.cycle_palette(),
+            k if k == hotkeys.fullscreen => self.toggle_fullscreen(),
+            k if k == hotkeys.pause => {
+                self.user_paused = !self.user_paused;
+                eprintln!(
+                    "{}",
+                    if self.user_paused {
+                        "paused"
+                    } else {
+                        "resumed"
+                    }
+                );
+            }
+            k if k == hotkeys.frame_advance => self.advance_frame(),
+            k if k == hotkeys.save_state => self.save_state(),
+            k if k == hotkeys.load_state => self.load_state(),
+            k if k == hotkeys.next_slot => self.select_slot(self.slot + 1),
+            k if k == hotkeys.previous_slot => self.select_slot(self.slot + STATE_SLOTS - 1),
+            k if k == hotkeys.fast_forward => self.fast_forward_held = true,
+            k if k == hotkeys.rewind && self.rewind.is_some() => self.start_rewind(),
+            k if k == hotkeys.fast_forward_toggle => {
+                self.fast_forward_locked = !self.fast_forward_locked;
+                eprintln!(
+                    "fast-forward {}",
+                    if self.fast_forward_locked {
+                        "on"
+                    } else {
+                        "off"
+                    }
+                );
+            }
+            k if k == hotkeys.slow_motion => {
+                self.slow_motion_on = !self.slow_motion_on;
+                eprintln!(
+                    "slow motion {}",
+                    if self.slow_motion_on { "on" } else { "off" }
+                );
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn set_audio_latency(&mut self, latency_ms: u32) {
+        if let Some(audio) = &mut self.audio {
+            audio.set_latency(latency_ms);
+        }
+    }
+
+    pub fn set_audio_sync(&mut self, audio_sync: bool) {
+        self.audio_sync = audio_sync;
+    }
+
+    pub fn set_fullscreen(&mut self, fullscreen: bool) {
+        self.lcd.set_fullscreen(fullscreen);
+    }
+
+    pub fn set_integer_scale(&mut self, integer_scale: bool) {
+        self.lcd.set_integer_scale(integer_scale);
+    }
+
+    pub fn set_pause_on_focus_loss(&mut self, pause: bool) {
+        self.pause_on_focus_loss = pause;
+    }
+
+    fn toggle_fullscreen(&mut self) {
+        let fullscreen = !self.lcd.is_fullscreen();
+        self.lcd.set_fullscreen(fullscreen);
+        self.config.fullscreen = Some(fullscreen);
+        self.save_config();
+    }
+
+    fn paused(&self) -> bool {
+        self.user_paused || (self.pause_on_focus_loss && !self.focused)
+    }
+
+    // Pauses and runs a single frame, its audio is dropped
+    fn advance_frame(&mut self) {
+        self.user_paused = true;
+        self.gameboy.run_frame();
+        self.frame_done();
+        self.lcd.draw(self.gameboy.frame_buffer());
+        self.gameboy.flush_audio();
+    }
+
+    pub fn set_fast_forward(&mut self, speed: f64) {
+        self.fast_forward = speed;
+    }
+
+    pub fn set_slow_motion(&mut self, speed: f64) {
+        self.slow_motion = speed;
+    }
+
+    // Speed after fast-forward and slow motion, None when running as fast as possible
+    fn current_speed(&self) -> Option<f64> {
+        if self.fast_forward_held || self.fast_forward_locked {
+            (self.fast_forward > 0.0).then_some(self.fast_forward)
+        } else if self.slow_motion_on {
+            Some(self.slow_motion)
+        } else {
+            Some(self.speed)
+        }
+    }
+
+    // Snapshots every `interval` frames within `budget` MiB, 0 disables rewinding
+    pub fn set_rewind(&mut self, interval: u32, budget: u32) {
+        self.rewind = (budget > 0).then(|| Rewind::new(interval, budget as usize * 1024 * 1024));
+    }
+
+    pub fn set_rewind_speed(&mut self, speed: f64) {
+        self.rewind_speed = speed;
+    }
+
+    // Takes a rewind snapshot after a frame when one is due
+    fn frame_done(&mut self) {
+        if let Some(rewind) = &mut self.rewind {
+            if rewind.frame_done() {
+                rewind.push(self.gameboy.save_state());
+            }
+        }
+    }
+
+    fn start_rewind(&mut self) {
+        if let Some(rewind) = &self.rewind {
+            self.rewinding = true;
+            // Step back on the first frame
+            self.rewind_progress = rewind.interval() as f64;
+        }
+    }
+
+    // Steps back through the snapshots and shows where it lands, paced like
+    // frames at normal speed
+    fn rewind_frame(&mut self, deadline: &mut time::Instant) {
+        let Some(rewind) = &mut self.rewind else {
+            self.rewinding = false;
+            return;
+        };
+        self.rewind_progress += self.rewind_speed;
+        let interval = rewind.interval() as f64;
+        let mut stepped = false;
+        while self.rewind_progress >= interval {
+            self.rewind_progress -= interval;
+            rewind.step_back();
+            stepped = true;
+        }
+        if let Some(state) = rewind.latest().filter(|_| stepped) {
+            match self.gameboy.load_snapshot(state) {
+                Ok(()) => self.lcd.draw(self.gameboy.frame_buffer()),
+                Err(e) => eprintln!("failed to rewind: {}", e),
+            }
+        }
+        self.handle_events();
+        self.wait_for_frame(deadline, Some(1.0));
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+
+    pub fn key_bindings_mut(&mut self) -> &mut KeyBindings {
+        &mut self.keys
+    }
+
+    pub fn controllers_mut(&mut self) -> &mut Controllers {
+        &mut self.controllers
+    }
+
+    fn handle_events(&mut self) {
+        while let Some(event) = self.events.poll_event() {
+            self.handle_event(event);
+        }
+    }
+
+    // Sleeps until an event arrives, used while the emulation is paused
+    fn wait_events(&mut self) {
+        if let Some(event) = self.events.wait_event_timeout(100) {
+            self.handle_event(event);
+        }
+        self.handle_events();
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        if self.controllers.handle(&event, self.gameboy.joypad_mut()) {
+            return;
+        }
+        match event {
+            Event::Quit { .. } => self.quit = true,
+            Event::Window { win_event, .. } => match win_event {
+                WindowEvent::Close => self.quit = true,
+                WindowEvent::FocusGained => self.focused = true,
+                WindowEvent::FocusLost => {
+                    self.focused = false;
+                    self.rewinding = false;
+                    // Keys released while unfocused never send a KeyUp
+                    self.gameboy.joypad_mut().release_all();
+                }
+                // Redraw right away so the letterbox follows the window even while paused
+                WindowEvent::Resized(..) | WindowEvent::SizeChanged(..) | WindowEvent::Exposed => {
+                    self.lcd.draw(self.gameboy.frame_buffer());
+                }
+                _ => (),
+            },
+            Event::KeyDown {
+                keycode: Some(key),
+                repeat,
+                ..
+            } => {
+                // Held hotkeys repeat, those repeats are swallowed as well
+                let hotkey = if repeat {
+                    self.config.hotkeys.action(key).is_some()
+                } else {
+                    self.hotkey(key)
+                };
+                if hotkey {
+                    return;
+                }
+                if let Some(button) = self.keys.button(key) {
+                    self.gameboy.joypad_mut().press(button);
+                }
+            }
+            Event::KeyUp {
+                keycode: Some(key), ..
+            } => {
+                if let Some(button) = self.keys.button(key) {
+                    self.gameboy.joypad_mut().release(button);
+                }
+                if key == self.config.hotkeys.fast_forward {
+                    self.fast_forward_held = false;
+                }
+                if key == self.config.hotkeys.rewind {
+                    self.rewinding = false;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn flush_audio(&mut self) {
+        // Mute at other speeds rather than play pitch shifted or choppy audio
+        let normal_speed = self.current_speed() == Some(1.0);
+        if let Some(audio) = &mut self.audio {
+            if normal_speed {
+                audio.queue(self.gameboy.audio_samples());
+                if !self.audio_sync {
+                    self.gameboy.set_audio_ratio(audio.rate_ratio());
+                }
+            }
+        }
+        self.gameboy.flush_audio();
+    }
+
+    fn run_cycles(&mut self, cycles: u32) {
+        if self.gameboy.run_cycles(cycles) {
+            self.frame_done();
+            self.lcd.draw(self.gameboy.frame_buffer());
+            self.handle_events();
+            self.flush_audio();
+        }
+    }
+
+    // Runs until the window is closed
+    pub fn run(&mut self) {
+        let mut deadline = time::Instant::now();
+
+        while !self.quit {
+            if self.rewinding {
+                self.rewind_frame(&mut deadline);
+            } else if self.paused() {
+                self.wait_events();
+                // Don't catch up on the time spent paused
+                deadline = time::Instant::now();
+            } else if self.audio_sync && self.audio.is_some() && self.current_speed() == Some(1.0) {
+                self.run_audio_synced();
+                deadline = time::Instant::now();
+            } else {
+                self.run_frame_paced(&mut deadline);
+            }
+        }
+
+        // Save captures in progress, the WAV recording is finalized when dropped
+        if self.gameboy.is_vgm_logging() {
+            self.toggle_vgm_log();
+        }
+        self.gameboy.stop_recording();
+    }
+
+    // Emulated time of one frame at `speed`
+    fn frame_duration(speed: f64) -> time::Duration {
+        time::Duration::from_secs_f64(FRAME_M_CYCLES as f64 / M_CYCLE_HZ as f64 / speed)
+    }
+
+    // Runs a frame and waits until the next one is due
+    fn run_frame_paced(&mut self, deadline: &mut time::Instant) {
+        let speed = self.current_speed();
+        self.gameboy.run_frame();
+        self.frame_done();
+        // Above normal speed only draw as often as the real hardware would
+        if speed.is_some_and(|speed| speed <= 1.0)
+            || self.last_draw.elapsed() >= Self::frame_duration(1.0)
+        {
+            self.lcd.draw(self.gameboy.frame_buffer());
+            self.last_draw = time::Instant::now();
+        }
+        self.handle_events();
+        self.flush_audio();
+        self.wait_for_frame(deadline, speed);
+    }
+
+    // Sleeps until the next frame at `speed` is due, or lets presenting the
+    // frame wait for the display when vsync is on at normal speed
+    fn wait_for_frame(&self, deadline: &mut time::Instant, speed: Option<f64>) {
+        let now = time::Instant::now();
+        let Some(speed) = speed else {
+            *deadline = now;
+            return;
+        };
+        if self.lcd.vsync() && speed == 1.0 {
+            *deadline = now;
+            return;
+        }
+
+        let frame = Self::frame_duration(speed);
+        *deadline += frame;
+        if *deadline > now {
+            thread::sleep(*deadline - now);
+        } else if now - *deadline > frame * MAX_LAG_FRAMES {
+            // Drop the time lost to a long stall instead of fast-forwarding through it
+            *deadline = now;
+        }
+    }
+
+    // Uses the audio device as the master clock, emulating whenever its buffer runs low
+    fn run_audio_synced(&mut self) {
+        if self
+            .audio
+            .as_ref()
+            .is_some_and(|audio| audio.below_target())
+        {
+            // 1 ms worth of M-cycles
+            self.run_cycles(M_CYCLE_HZ / 1000);
+            self.flush_audio();
+        } else {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -1,40 +1,415 @@
-use crate::{bootrom::Bootrom, constants::M_CYCLE_NANOS, cpu::Cpu, lcd::Lcd, mem::Memory};
-use sdl2;
-use std::time;
+use crate::{
+    bess,
+    bootrom::Bootrom,
+    cartridge::Cartridge,
+    constants::FRAME_M_CYCLES,
+    cpu::Cpu,
+    joypad::Joypad,
+    mem::{self, Memory},
+    palette::Palette,
+    ppu::Renderer,
+    serial::SerialEndpoint,
+    state::{Reader, Writer, MAGIC},
+};
+#[cfg(feature = "std")]
+use crate::{
+    constants::{AUDIO_RATE, M_CYCLE_HZ},
+    recorder::Recorder,
+    resampler::Resampler,
+};
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use std::{io, path::Path};
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Model {
+    Dmg,
+    Mgb, // Game Boy Pocket
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "dmg" => Some(Model::Dmg),
+            "mgb" | "pocket" => Some(Model::Mgb),
+            _ => None,
+        }
+    }
+}
+
+// The emulated system on its own, frontends drive it and present its output
 pub struct Gameboy {
+    model: Model,
     cpu: Cpu,
     mem: Memory,
-    lcd: Lcd,
+    #[cfg(feature = "std")]
+    resampler: Resampler,
+    #[cfg(feature = "std")]
+    recorder: Option<Recorder>,
 }
 
 impl Gameboy {
-    pub fn new(bootrom: Bootrom) -> Self {
-        let sdl = sdl2::init().expect("failed to init SDL");
-        let lcd = Lcd::new(&sdl, 4);
+    // Without a boot ROM the system starts in the state `model`'s boot ROM leaves behind
+    pub fn new(model: Model, bootrom: Option<Bootrom>) -> Self {
+        let skip_boot = bootrom.is_none();
+        let mut gameboy = Self {
+            model,
+            cpu: Cpu::new(),
+            mem: Memory::new(bootrom),
+            #[cfg(feature = "std")]
+            resampler: Resampler::new(M_CYCLE_HZ, AUDIO_RATE),
+            #[cfg(feature = "std")]
+            recorder: None,
+        };
+        if skip_boot {
+            gameboy.skip_boot(model);
+        }
+        gameboy
+    }
+
+    fn skip_boot(&mut self, model: Model) {
+        let regs = &mut self.cpu.regs;
+        regs.a = match model {
+            Model::Dmg => 0x01,
+            Model::Mgb => 0xff,
+        };
+        regs.f = 0xb0;
+        (regs.b, regs.c) = (0x00, 0x13);
+        (regs.d, regs.e) = (0x00, 0xd8);
+        (regs.h, regs.l) = (0x01, 0x4d);
+        regs.sp = 0xfffe;
+        regs.pc = 0x0100;
+
+        // The boot sound has faded out by now, so the channels aren't retriggered
+        let io = [
+            (0xff26, 0x80),
+            (0xff10, 0x80),
+            (0xff11, 0xbf),
+            (0xff12, 0xf3),
+            (0xff14, 0x3f),
+            (0xff16, 0x3f),
+            (0xff19, 0x3f),
+            (0xff1a, 0x7f),
+            (0xff1b, 0xff),
+            (0xff1c, 0x9f),
+            (0xff1e, 0x3f),
+            (0xff20, 0xff),
+            (0xff23, 0x3f),
+            (0xff24, 0x77),
+            (0xff25, 0xf3),
+            (0xff47, 0xfc),
+            (0xff40, 0x91),
+            (0xff0f, 0xe1),
+        ];
+        for (addr, data) in io {
+            self.mem.write(addr, data);
+        }
+    }
+
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+        self.mem.cartridge = Some(cartridge);
+    }
+
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.mem.cartridge.as_ref()
+    }
+
+    // Title from the cartridge header, empty without a cartridge
+    pub fn title(&self) -> String {
+        self.cartridge()
+            .map_or(String::new(), |cartridge| cartridge.header().title.clone())
+    }
+
+    pub fn start_vgm_log(&mut self) {
+        self.mem.apu.start_vgm_log();
+    }
+
+    // Returns the VGM file, if logging was active
+    pub fn stop_vgm_log(&mut self) -> Option<Vec<u8>> {
+        let title = self.title();
+        self.mem
+            .apu
+            .stop_vgm_log()
+            .map(|mut vgm| vgm.finish(&title))
+    }
+
+    pub fn is_vgm_logging(&self) -> bool {
+        self.mem.apu.is_vgm_logging()
+    }
 
-        let mem = Memory::new(bootrom);
-        let cpu = Cpu::new();
+    // Returns false if logging isn't active
+    pub fn mark_vgm_loop(&mut self) -> bool {
+        let Some(vgm) = self.mem.apu.vgm_log_mut() else {
+            return false;
+        };
+        vgm.mark_loop();
+        true
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.mem.ppu.set_renderer(renderer);
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.mem.ppu.set_palette(palette);
+    }
+
+    pub fn set_serial_endpoint(&mut self, endpoint: Box<dyn SerialEndpoint>) {
+        self.mem.serial.set_endpoint(endpoint);
+    }
+
+    // Bytes sent over the serial port since the last call, if the default endpoint is in use
+    pub fn serial_output(&mut self) -> Vec<u8> {
+        self.mem.serial.take_output()
+    }
 
-        Self { cpu, mem, lcd }
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        &mut self.mem.joypad
     }
 
-    pub fn run(&mut self) {
-        let time = time::Instant::now();
-        let mut elapsed = 0;
+    // The last completed frame as RGB24
+    pub fn frame_buffer(&self) -> &[u8] {
+        self.mem.ppu.frame_buffer()
+    }
 
-        loop {
-            let e = time.elapsed().as_nanos();
+    // Advances the whole system by one M-cycle, returns true when a frame is completed
+    fn step(&mut self) -> bool {
+        self.cpu.emu(&mut self.mem);
+        self.mem.timer.emu(&mut self.mem.interrupts);
+        self.mem.joypad.emu(&mut self.mem.interrupts);
+        if let Some(cartridge) = &mut self.mem.cartridge {
+            cartridge.emu();
+        }
+        self.mem
+            .serial
+            .emu(self.mem.timer.div(), &mut self.mem.interrupts);
+        self.mem.apu.emu(self.mem.timer.div());
+        #[cfg(feature = "std")]
+        {
+            self.resampler.push(self.mem.apu.sample());
+            if let Some(recorder) = &mut self.recorder {
+                recorder.emu(&self.mem.apu);
+            }
+        }
 
-            for _ in 0..(e - elapsed) / M_CYCLE_NANOS {
-                self.cpu.emu(&mut self.mem);
+        self.mem.ppu.emu()
+    }
 
-                if self.mem.ppu.emu() {
-                    self.lcd.draw(self.mem.ppu.pixel_buffer());
-                }
+    // Runs `cycles` M-cycles, returns true if a frame was completed meanwhile
+    pub fn run_cycles(&mut self, cycles: u32) -> bool {
+        let mut frame = false;
+        for _ in 0..cycles {
+            frame |= self.step();
+        }
+        frame
+    }
 
-                elapsed += M_CYCLE_NANOS;
+    // Runs until the PPU completes a frame, or for a frame's worth of time while the LCD is off
+    pub fn run_frame(&mut self) {
+        for _ in 0..FRAME_M_CYCLES {
+            if self.step() {
+                return;
             }
         }
     }
 }
+
+// Save states
+impl Gameboy {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.chunk(b"INFO", |w| {
+            let (title, hash) = self.rom_identity();
+            w.blob(title.as_bytes());
+            w.u32(hash);
+        });
+        w.chunk(b"CPU ", |w| self.cpu.save(w));
+        self.mem.save(&mut w);
+        w.finish()
+    }
+
+    // gemu's own state followed by the same state as BESS blocks, which other
+    // emulators can load. `timestamp` is the UNIX time the MBC3 clock is saved at
+    pub fn save_bess(&self, timestamp: u64) -> Vec<u8> {
+        let mut data = self.save_state();
+        bess::write(&mut data, &self.cpu, &self.mem, self.model, timestamp);
+        data
+    }
+
+    // Takes gemu's own states, including those inside BESS files, and BESS
+    // states from other emulators. Leaves the system as it was if the state
+    // can't be loaded
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let backup = self.save_state();
+        self.read_state(data)
+            .map_err(|e| match self.read_state(&backup) {
+                Ok(()) => e,
+                Err(restore) => format!(
+                    "{}, then restoring the previous state failed: {}",
+                    e, restore
+                ),
+            })
+    }
+
+    // Loads a state saved earlier in this session, like a rewind snapshot,
+    // without the backup `load_state()` takes since it can't be rejected
+    pub fn load_snapshot(&mut self, data: &[u8]) -> Result<(), String> {
+        self.read_state(data)
+    }
+
+    fn rom_identity(&self) -> (&str, u32) {
+        match &self.mem.cartridge {
+            Some(cartridge) => (&cartridge.header().title, cartridge.rom_hash()),
+            None => ("", 0),
+        }
+    }
+
+    fn read_state(&mut self, data: &[u8]) -> Result<(), String> {
+        if !data.starts_with(&MAGIC) && bess::is_bess(data) {
+            return bess::read(data, &mut self.cpu, &mut self.mem);
+        }
+        let mut reader = Reader::new(data)?;
+        let mut chunks = Vec::new();
+        while let Some(chunk) = reader.chunk()? {
+            chunks.push(chunk);
+        }
+
+        let Some((_, info)) = chunks.iter_mut().find(|(tag, _)| tag == b"INFO") else {
+            return Err("the save state has no ROM information".into());
+        };
+        let title = String::from_utf8_lossy(info.blob()?).into_owned();
+        let hash = info.u32()?;
+        if hash != self.rom_identity().1 {
+            return Err(format!(
+                "the save state was made with a different ROM ({})",
+                if title.is_empty() { "untitled" } else { &title }
+            ));
+        }
+
+        let cartridge = self.mem.cartridge.as_ref().map(|_| mem::CARTRIDGE_CHUNK);
+        for tag in [b"CPU "].into_iter().chain(mem::CHUNKS).chain(cartridge) {
+            if !chunks.iter().any(|(t, _)| t == tag) {
+                return Err(format!(
+                    "the save state has no {} chunk",
+                    String::from_utf8_lossy(tag).trim_end()
+                ));
+            }
+        }
+
+        for (tag, mut r) in chunks {
+            match &tag {
+                b"INFO" => (),
+                b"CPU " => self.cpu.load(&mut r)?,
+                tag => self.mem.load_chunk(tag, &mut r)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Audio output and recording, the resampler needs std for its math
+#[cfg(feature = "std")]
+impl Gameboy {
+    pub fn start_recording(&mut self, path: &Path, per_channel: bool) -> io::Result<()> {
+        self.recorder = Some(Recorder::create(path, AUDIO_RATE, per_channel)?);
+        Ok(())
+    }
+
+    // Returns false if no recording was active
+    pub fn stop_recording(&mut self) -> bool {
+        self.recorder.take().is_some()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Interleaved stereo samples at AUDIO_RATE, these pile up until `flush_audio()`
+    pub fn audio_samples(&self) -> &[f32] {
+        self.resampler.samples()
+    }
+
+    // Stretches the audio output by `ratio` for dynamic rate control
+    pub fn set_audio_ratio(&mut self, ratio: f64) {
+        self.resampler.set_ratio(ratio);
+    }
+
+    // Writes out the pending recording and discards the played samples
+    pub fn flush_audio(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.write() {
+                eprintln!("failed to write recording: {}", e);
+                self.recorder = None;
+            }
+        }
+        self.resampler.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // Calls a subroutine that increments B in a loop, from the boot ROM
+    fn machine() -> Gameboy {
+        let mut rom = vec![0; 0x100];
+        // LD SP, $FFFE; CALL $0010; JR -5
+        rom[..8].copy_from_slice(&[0x31, 0xfe, 0xff, 0xcd, 0x10, 0x00, 0x18, 0xfb]);
+        // INC B; RET
+        rom[0x10..0x12].copy_from_slice(&[0x04, 0xc9]);
+        Gameboy::new(Model::Dmg, Some(Bootrom::new(rom.into_boxed_slice())))
+    }
+
+    #[test]
+    fn states_keep_the_instruction_in_progress() {
+        for cycles in 1..40 {
+            let mut gameboy = machine();
+            gameboy.run_cycles(cycles);
+            let state = gameboy.save_state();
+            assert_eq!(gameboy.save_state(), state);
+
+            let mut loaded = machine();
+            loaded.load_state(&state).unwrap();
+            assert_eq!(loaded.save_state(), state);
+            gameboy.run_cycles(100);
+            loaded.run_cycles(100);
+            assert_eq!(loaded.save_state(), gameboy.save_state());
+        }
+    }
+
+    #[test]
+    fn failed_loads_leave_the_state_alone() {
+        let mut gameboy = machine();
+        gameboy.run_cycles(30);
+        let mut state = gameboy.save_state();
+        // LY past the last line, in the PPU chunk after the CPU chunk
+        let ppu = state.windows(4).position(|tag| tag == b"PPU ").unwrap();
+        state[ppu + 13] = 200;
+
+        let mut loaded = machine();
+        loaded.run_cycles(7);
+        let before = loaded.save_state();
+        assert!(loaded.load_state(&state).is_err());
+        assert_eq!(loaded.save_state(), before);
+    }
+
+    #[test]
+    fn serial_interrupt_wakes_halt() {
+        let mut rom = vec![0; 0x100];
+        // LD SP, $FFFE; LD A, $81; LDH (SC), A; LD A, $08; LDH (IE), A; EI; HALT
+        rom[..15].copy_from_slice(&[
+            0x31, 0xfe, 0xff, 0x3e, 0x81, 0xe0, 0x02, 0x3e, 0x08, 0xe0, 0xff, 0xfb, 0x76, 0x18,
+            0xfd,
+        ]);
+        // Serial handler: LD A, $42; LDH ($80), A; RETI
+        rom[0x58..0x5d].copy_from_slice(&[0x3e, 0x42, 0xe0, 0x80, 0xd9]);
+        let mut gameboy = Gameboy::new(Model::Dmg, Some(Bootrom::new(rom.into_boxed_slice())));
+
+        gameboy.run_cycles(1000);
+        assert_eq!(gameboy.mem.read(0xff80), 0);
+        gameboy.run_cycles(100);
+        assert_eq!(gameboy.mem.read(0xff80), 0x42);
+        assert_eq!(gameboy.serial_output(), [0]);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::state::{Reader, Writer};
+use alloc::{boxed::Box, string::String};
+
 #[derive(Clone)]
 pub struct Hram(
     Box<[u8; 0x80]>, // HRAM has 128 Bytes
@@ -15,4 +18,20 @@ impl Hram {
     pub fn write(&mut self, addr: u16, data: u8) {
         self.0[(addr as usize) & 0x7f] = data;
     }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut_slice()
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        w.bytes(self.0.as_slice());
+    }
+
+    pub fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        r.fill(self.0.as_mut_slice())
+    }
 }
@@ -0,0 +1,54 @@
+use crate::state::{Reader, Writer};
+use alloc::string::String;
+
+#[derive(Clone, Default)]
+pub struct Interrupts {
+    int_flags: u8,
+    int_enable: u8,
+}
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn irq(&mut self, val: u8) {
+        self.int_flags |= val;
+    }
+
+    // Requested interrupts that are also enabled
+    pub fn pending(&self) -> u8 {
+        self.int_flags & self.int_enable & 0x1f
+    }
+
+    pub fn ack(&mut self, val: u8) {
+        self.int_flags &= !val;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff0f => 0xe0 | self.int_flags,
+            0xffff => self.int_enable,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xff0f => self.int_flags = data & 0x1f,
+            0xffff => self.int_enable = data,
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.u8(self.int_flags);
+        w.u8(self.int_enable);
+    }
+
+    pub(crate) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.int_flags = r.u8()? & 0x1f;
+        self.int_enable = r.u8()?;
+        Ok(())
+    }
+}
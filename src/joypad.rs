@@ -0,0 +1,125 @@
+use crate::{
+    constants::{JOYPAD_INT, SELECT_ACTION, SELECT_DIRECTION},
+    interrupts::Interrupts,
+    state::{Reader, Writer},
+};
+use alloc::string::String;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Button {
+    Right = 0,
+    Left = 1,
+    Up = 2,
+    Down = 3,
+    A = 4,
+    B = 5,
+    Select = 6,
+    Start = 7,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Button::Right => "right",
+            Button::Left => "left",
+            Button::Up => "up",
+            Button::Down => "down",
+            Button::A => "a",
+            Button::B => "b",
+            Button::Select => "select",
+            Button::Start => "start",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|b| b.name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Clone)]
+pub struct Joypad {
+    select: u8,  // P14/P15 select lines as written, active low
+    pressed: u8, // One bit per button, directions in the lower nibble
+    lines: u8,   // P10-P13 as last seen by the interrupt logic
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: SELECT_ACTION | SELECT_DIRECTION,
+            pressed: 0,
+            lines: 0x0f,
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.pressed |= 1 << button as u8;
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !(1 << button as u8);
+    }
+
+    pub fn release_all(&mut self) {
+        self.pressed = 0;
+    }
+
+    // P10-P13, a pressed button pulls its line low while its group is selected
+    fn input_lines(&self) -> u8 {
+        let mut low = 0;
+        if self.select & SELECT_DIRECTION == 0 {
+            low |= self.pressed & 0x0f;
+        }
+        if self.select & SELECT_ACTION == 0 {
+            low |= self.pressed >> 4;
+        }
+        !low & 0x0f
+    }
+
+    pub fn read(&self, _addr: u16) -> u8 {
+        0xc0 | self.select | self.input_lines()
+    }
+
+    pub fn write(&mut self, _addr: u16, data: u8) {
+        self.select = data & (SELECT_ACTION | SELECT_DIRECTION);
+    }
+
+    // Buttons held are left alone, they follow the host's input
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.u8(self.select);
+        w.u8(self.lines);
+    }
+
+    pub(crate) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.select = r.u8()? & (SELECT_ACTION | SELECT_DIRECTION);
+        self.lines = r.u8()? & 0x0f;
+        Ok(())
+    }
+
+    pub fn emu(&mut self, interrupts: &mut Interrupts) {
+        let lines = self.input_lines();
+        if self.lines & !lines > 0 {
+            interrupts.irq(JOYPAD_INT);
+        }
+        self.lines = lines;
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
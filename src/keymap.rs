@@ -0,0 +1,46 @@
+use crate::joypad::Button;
+use sdl2::keyboard::Keycode;
+use std::collections::HashMap;
+
+pub struct KeyBindings(HashMap<Keycode, Button>);
+
+impl KeyBindings {
+    pub fn new() -> Self {
+        Self(HashMap::from([
+            (Keycode::Right, Button::Right),
+            (Keycode::Left, Button::Left),
+            (Keycode::Up, Button::Up),
+            (Keycode::Down, Button::Down),
+            (Keycode::X, Button::A),
+            (Keycode::Z, Button::B),
+            (Keycode::Backspace, Button::Select),
+            (Keycode::Return, Button::Start),
+        ]))
+    }
+
+    // Binds `key` to `button`, a button may be bound to several keys
+    pub fn bind(&mut self, key: Keycode, button: Button) {
+        self.0.insert(key, button);
+    }
+
+    pub fn button(&self, key: Keycode) -> Option<Button> {
+        self.0.get(&key).copied()
+    }
+
+    // Parses a binding written as `<key>=<button>`, e.g. `Space=start`
+    pub fn parse(s: &str) -> Result<(Keycode, Button), String> {
+        let (key, button) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid binding `{}`, expected <key>=<button>", s))?;
+        let key = Keycode::from_name(key).ok_or_else(|| format!("unknown key `{}`", key))?;
+        let button =
+            Button::from_name(button).ok_or_else(|| format!("unknown button `{}`", button))?;
+        Ok((key, button))
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,11 +1,22 @@
-use sdl2::{pixels::PixelFormatEnum, render::Canvas, video::Window, Sdl};
+use sdl2::{
+    pixels::{Color, PixelFormatEnum},
+    rect::Rect,
+    render::{Canvas, Texture},
+    video::{FullscreenType, Window},
+    Sdl,
+};
 
 use crate::constants::{LCD_HEIGHT, LCD_WIDTH};
 
-pub struct Lcd(Canvas<Window>);
+pub struct Lcd {
+    canvas: Canvas<Window>,
+    texture: Texture,
+    integer_scale: bool, // Only scale by whole multiples, leaving a wider border
+    vsync: bool,         // Presenting waits for the display refresh
+}
 
 impl Lcd {
-    pub fn new(sdl: &Sdl, scale: u32) -> Self {
+    pub fn new(sdl: &Sdl, scale: u32, vsync: bool) -> Self {
         let window = sdl
             .video()
             .expect("failed to init SDL video filesystem")
@@ -15,19 +26,72 @@ impl Lcd {
             .build()
             .expect("failed to create window");
 
-        let canvas = window.into_canvas().build().unwrap();
-        Self(canvas)
-    }
-
-    pub fn draw(&mut self, pixels: Box<[u8]>) {
-        let texture_creator = self.0.texture_creator();
-        let mut texture = texture_creator
+        let mut canvas = window.into_canvas();
+        if vsync {
+            canvas = canvas.present_vsync();
+        }
+        let canvas = canvas.build().unwrap();
+        let texture = canvas
+            .texture_creator()
             .create_texture_streaming(PixelFormatEnum::RGB24, LCD_WIDTH as u32, LCD_HEIGHT as u32)
             .unwrap();
+        Self {
+            canvas,
+            texture,
+            integer_scale: false,
+            vsync,
+        }
+    }
+
+    pub fn set_fullscreen(&mut self, fullscreen: bool) {
+        let mode = if fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+        if let Err(e) = self.canvas.window_mut().set_fullscreen(mode) {
+            eprintln!("failed to change fullscreen mode: {}", e);
+        }
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.canvas.window().fullscreen_state() != FullscreenType::Off
+    }
+
+    pub fn vsync(&self) -> bool {
+        self.vsync
+    }
+
+    pub fn set_integer_scale(&mut self, integer_scale: bool) {
+        self.integer_scale = integer_scale;
+    }
+
+    // Largest area keeping the aspect ratio of the LCD, centered in the window
+    fn viewport(&self) -> Rect {
+        let (width, height) = self.canvas.output_size().unwrap_or((0, 0));
+        let (lcd_width, lcd_height) = (LCD_WIDTH as u32, LCD_HEIGHT as u32);
+        let (w, h) = if self.integer_scale {
+            let scale = (width / lcd_width).min(height / lcd_height).max(1);
+            (lcd_width * scale, lcd_height * scale)
+        } else if width * lcd_height > height * lcd_width {
+            (height * lcd_width / lcd_height, height)
+        } else {
+            (width, width * lcd_height / lcd_width)
+        };
+        Rect::new(
+            (width as i32 - w as i32) / 2,
+            (height as i32 - h as i32) / 2,
+            w.max(1),
+            h.max(1),
+        )
+    }
 
-        texture.update(None, &pixels, 480).unwrap();
-        self.0.clear();
-        self.0.copy(&texture, None, None).unwrap();
-        self.0.present();
+    pub fn draw(&mut self, pixels: &[u8]) {
+        self.texture.update(None, pixels, LCD_WIDTH * 3).unwrap();
+        let viewport = self.viewport();
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        self.canvas.copy(&self.texture, None, viewport).unwrap();
+        self.canvas.present();
     }
 }
@@ -0,0 +1,49 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod apu;
+#[cfg(feature = "sdl")]
+pub mod audio;
+pub mod bess;
+pub mod bootrom;
+pub mod cartridge;
+#[cfg(feature = "sdl")]
+pub mod config;
+pub mod constants;
+#[cfg(feature = "sdl")]
+pub mod controller;
+pub mod cpu;
+#[cfg(feature = "sdl")]
+pub mod frontend;
+pub mod gameboy;
+mod hram;
+pub mod interrupts;
+pub mod joypad;
+#[cfg(feature = "sdl")]
+pub mod keymap;
+#[cfg(feature = "sdl")]
+pub mod lcd;
+#[cfg(feature = "std")]
+pub mod link;
+pub mod mem;
+pub mod palette;
+#[cfg(feature = "std")]
+pub mod png;
+pub mod ppu;
+#[cfg(feature = "std")]
+pub mod printer;
+#[cfg(feature = "std")]
+pub mod recorder;
+#[cfg(feature = "std")]
+pub mod resampler;
+pub mod rewind;
+pub mod serial;
+pub mod state;
+pub mod timer;
+#[cfg(feature = "std")]
+pub mod toml;
+pub mod vgm;
+#[cfg(feature = "std")]
+pub mod wav;
+mod wram;
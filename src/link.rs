@@ -0,0 +1,192 @@
+use crate::serial::SerialEndpoint;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+// Both sides exchange a sync message every quantum and wait for each other.
+// Shorter than a byte transfer at 8192 Hz (1024 M-cycles) so no transfer can be missed.
+const QUANTUM: u64 = 512;
+
+const MAGIC: &[u8; 4] = b"GLNK";
+
+const SENT: u8 = 1 << 0; // We clocked a transfer out during the last quantum
+const WAITING: u8 = 1 << 1; // We are waiting for the other side to clock a transfer
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+// Link cable to another gemu process.
+// Everything one side sees of the other is exchanged at quantum boundaries,
+// so both sides stay in lockstep and behave the same on every run.
+pub struct LinkCable {
+    stream: Option<Box<dyn Stream>>,
+    cycles: u64,
+    sent: Option<u8>,    // Byte we clocked out as master, delivered at the next sync
+    waiting: Option<u8>, // Byte we would send if the other side clocked a transfer
+    peer_out: Option<u8>, // The other side's `waiting` as of the last sync
+    received: Option<u8>, // Byte the other side clocked in to us
+}
+
+impl LinkCable {
+    // `addr` is either `host:port` or `unix:<path>`
+    pub fn listen(addr: &str) -> io::Result<Self> {
+        eprintln!("waiting for the link partner on {}", addr);
+        let stream: Box<dyn Stream> = match addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                let _ = std::fs::remove_file(path);
+                Box::new(UnixListener::bind(path)?.accept()?.0)
+            }
+            #[cfg(not(unix))]
+            Some(_) => return Err(io::ErrorKind::Unsupported.into()),
+            None => {
+                let stream = TcpListener::bind(addr)?.accept()?.0;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+        };
+        Self::handshake(stream)
+    }
+
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        let stream: Box<dyn Stream> = match addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Box::new(UnixStream::connect(path)?),
+            #[cfg(not(unix))]
+            Some(_) => return Err(io::ErrorKind::Unsupported.into()),
+            None => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+        };
+        Self::handshake(stream)
+    }
+
+    fn handshake(mut stream: Box<dyn Stream>) -> io::Result<Self> {
+        stream.write_all(MAGIC)?;
+        let mut magic = [0; 4];
+        stream.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the other side is not a gemu link cable",
+            ));
+        }
+        eprintln!("link cable connected");
+
+        Ok(Self {
+            stream: Some(stream),
+            cycles: 0,
+            sent: None,
+            waiting: None,
+            peer_out: None,
+            received: None,
+        })
+    }
+
+    fn exchange(stream: &mut dyn Stream, msg: [u8; 3]) -> io::Result<[u8; 3]> {
+        stream.write_all(&msg)?;
+        let mut peer = [0; 3];
+        stream.read_exact(&mut peer)?;
+        Ok(peer)
+    }
+
+    fn sync(&mut self) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+
+        let flags = if self.sent.is_some() { SENT } else { 0 }
+            | if self.waiting.is_some() { WAITING } else { 0 };
+        let msg = [
+            flags,
+            self.sent.take().unwrap_or(0xff),
+            self.waiting.unwrap_or(0xff),
+        ];
+        match Self::exchange(stream.as_mut(), msg) {
+            Ok([flags, sent, waiting]) => {
+                self.peer_out = (flags & WAITING > 0).then_some(waiting);
+                // A byte clocked in while we weren't ready for it is lost
+                self.received = (flags & SENT > 0 && self.waiting.is_some()).then_some(sent);
+            }
+            Err(e) => {
+                eprintln!("link cable disconnected: {}", e);
+                self.stream = None;
+                self.peer_out = None;
+                self.received = None;
+            }
+        }
+    }
+}
+
+impl SerialEndpoint for LinkCable {
+    fn transfer(&mut self, data: u8) -> u8 {
+        self.sent = Some(data);
+        self.peer_out.take().unwrap_or(0xff)
+    }
+
+    fn poll(&mut self, data: u8) -> Option<u8> {
+        self.waiting = Some(data);
+        self.received.take()
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+        if self.cycles.is_multiple_of(QUANTUM) {
+            self.sync();
+        }
+        // Refreshed by `poll` every M-cycle while a transfer is pending
+        self.waiting = None;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{SERIAL_INT, SERIAL_INTERNAL_CLOCK, SERIAL_TRANSFER},
+        interrupts::Interrupts,
+        serial::Serial,
+        timer::Timer,
+    };
+    use std::thread;
+
+    // Runs one side of the cable for 2048 M-cycles with a transfer started
+    // on the first, returns SB and whether the serial interrupt was raised
+    fn run(stream: UnixStream, sb: u8, sc: u8) -> (u8, bool) {
+        let cable = LinkCable::handshake(Box::new(stream)).unwrap();
+        let mut serial = Serial::new();
+        serial.set_endpoint(Box::new(cable));
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::new();
+        serial.write(0xff01, sb);
+        serial.write(0xff02, SERIAL_TRANSFER | sc);
+        for _ in 0..2048 {
+            timer.emu(&mut interrupts);
+            serial.emu(timer.div(), &mut interrupts);
+        }
+        let irq = interrupts.read(0xff0f) & SERIAL_INT > 0;
+        (serial.read(0xff01), irq)
+    }
+
+    #[test]
+    fn exchanges_a_byte() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let master = thread::spawn(move || run(a, 0x42, SERIAL_INTERNAL_CLOCK));
+        let slave = thread::spawn(move || run(b, 0x99, 0));
+        assert_eq!(master.join().unwrap(), (0x99, true));
+        assert_eq!(slave.join().unwrap(), (0x42, true));
+    }
+
+    #[test]
+    fn rejects_other_peers() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        b.write_all(b"NOPE").unwrap();
+        let err = LinkCable::handshake(Box::new(a)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
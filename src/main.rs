@@ -1,35 +1,457 @@
-mod bootrom;
-mod constants;
-mod cpu;
-mod gameboy;
-mod hram;
-mod lcd;
-mod mem;
-mod ppu;
-mod wram;
+use gemu::{
+    bootrom::Bootrom,
+    cartridge::{Cartridge, Header},
+    config::Config,
+    constants::{LCD_HEIGHT, LCD_WIDTH},
+    controller::Controllers,
+    frontend::Frontend,
+    gameboy::{Gameboy, Model},
+    joypad::Button,
+    keymap::KeyBindings,
+    link::LinkCable,
+    palette::Palette,
+    png,
+    ppu::Renderer,
+    printer::Printer,
+    serial,
+};
+use sdl2::{controller::Button as PadButton, keyboard::Keycode};
+use std::{
+    env,
+    fmt::Display,
+    fs,
+    io::{self, Write},
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use std::{env, fs::File, io::Read, process::exit};
+const USAGE: &str = "\
+Usage: gemu run <rom> [options]
+       gemu info <rom>
+       gemu help
 
-fn file2vec(fname: &String) -> Vec<u8> {
-    if let Ok(mut file) = File::open(fname) {
-        let mut ret = vec![];
-        file.read_to_end(&mut ret).unwrap();
-        ret
-    } else {
-        panic!("failed to open {}.", fname);
+Options for run:
+  --config <file>           Settings file instead of $XDG_CONFIG_HOME/gemu/config.toml
+  --bootrom <file>          Boot through a boot ROM instead of skipping it
+  --model <dmg|mgb>         Hardware model to start as without a boot ROM
+  --scale <n>               Window scale factor (default 4)
+  --fullscreen              Start in fullscreen
+  --vsync                   Pace frames by the display refresh instead of a timer
+  --integer-scale           Only scale the picture by whole multiples
+  --pause-on-focus-loss     Pause while the window is in the background
+  --speed <factor>          Emulation speed relative to the hardware (default 1)
+  --fast-forward <factor>   Fast-forward speed, 0 for as fast as possible (default 0)
+  --slow-motion <factor>    Slow motion speed (default 0.5)
+  --rewind-budget <MiB>     Memory kept for rewinding, 0 to disable (default 64)
+  --rewind-interval <n>     Frames between rewind snapshots (default 2)
+  --rewind-speed <factor>   Rewind speed (default 1)
+  --palette <name|file>     Color palette, a built-in name or a palette file
+  --accurate-ppu            Use the pixel FIFO renderer
+  --load-state <file>       Start from a save state, gemu's own or BESS
+  --save-state <file>       Save the state when a headless run ends, as BESS
+  --headless                Run without a window, printing serial output to stdout
+  --frames <n>              Stop after n frames when headless
+  --screenshot <png>        Save the last frame when a headless run ends
+  --audio-sync              Pace emulation by the audio device
+  --latency <ms>            Target audio latency
+  --record <wav>            Record audio from the start
+  --record-channels         Also record each channel on its own
+  --bind <key>=<button>     Bind a keyboard key
+  --pad-bind <pad>=<button> Bind a game controller button
+  --deadzone <n>            Analog stick deadzone (0-32767)
+  --serial-stdout           Print bytes sent over the serial port
+  --printer                 Connect a Game Boy Printer
+  --link-listen <addr>      Wait for a link cable partner on <host>:<port> or unix:<path>
+  --link-connect <addr>     Connect the link cable to a listening partner";
+
+// Exit codes
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+fn usage_error(msg: impl Display) -> ! {
+    eprintln!("{}", msg);
+    eprintln!("Run `gemu help` for usage.");
+    exit(EXIT_USAGE);
+}
+
+fn fail(msg: impl Display) -> ! {
+    eprintln!("{}", msg);
+    exit(EXIT_FAILURE);
+}
+
+fn read_file(fname: &str) -> Vec<u8> {
+    fs::read(fname).unwrap_or_else(|e| fail(format!("failed to open {}: {}", fname, e)))
+}
+
+enum SerialOption {
+    Stdout,
+    Printer,
+    Listen(String),
+    Connect(String),
+}
+
+struct Options {
+    rom: Option<String>,
+    config: Option<PathBuf>,
+    bootrom: Option<String>,
+    model: Model,
+    scale: Option<u32>,
+    fullscreen: bool,
+    integer_scale: bool,
+    vsync: bool,
+    pause_on_focus_loss: bool,
+    speed: f64,
+    fast_forward: Option<f64>,
+    slow_motion: Option<f64>,
+    rewind_budget: Option<u32>,
+    rewind_interval: Option<u32>,
+    rewind_speed: Option<f64>,
+    palette: Option<Palette>,
+    renderer: Renderer,
+    load_state: Option<String>,
+    save_state: Option<String>,
+    headless: bool,
+    frames: Option<u32>,
+    screenshot: Option<String>,
+    audio_sync: bool,
+    latency: Option<u32>,
+    record: Option<String>,
+    record_channels: bool,
+    key_binds: Vec<(Keycode, Button)>,
+    pad_binds: Vec<(PadButton, Button)>,
+    deadzone: Option<i16>,
+    serial: Option<SerialOption>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Self {
+        let mut options = Self {
+            rom: None,
+            config: None,
+            bootrom: None,
+            model: Model::Dmg,
+            scale: None,
+            fullscreen: false,
+            integer_scale: false,
+            vsync: false,
+            pause_on_focus_loss: false,
+            speed: 1.0,
+            fast_forward: None,
+            slow_motion: None,
+            rewind_budget: None,
+            rewind_interval: None,
+            rewind_speed: None,
+            palette: None,
+            renderer: Renderer::Scanline,
+            load_state: None,
+            save_state: None,
+            headless: false,
+            frames: None,
+            screenshot: None,
+            audio_sync: false,
+            latency: None,
+            record: None,
+            record_channels: false,
+            key_binds: vec![],
+            pad_binds: vec![],
+            deadzone: None,
+            serial: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |what: &str| match args.next() {
+                Some(value) => value.clone(),
+                None => usage_error(format!("{} requires {}.", arg, what)),
+            };
+            match arg.as_str() {
+                "--config" => options.config = Some(value("a config file name").into()),
+                "--bootrom" => options.bootrom = Some(value("a boot ROM file name")),
+                "--model" => {
+                    let name = value("a model name");
+                    options.model = Model::from_name(&name)
+                        .unwrap_or_else(|| usage_error(format!("unknown model `{}`", name)));
+                }
+                "--scale" => options.scale = Some(parse(arg, &value("a scale factor"), 1..=16)),
+                "--fullscreen" => options.fullscreen = true,
+                "--integer-scale" => options.integer_scale = true,
+                "--vsync" => options.vsync = true,
+                "--pause-on-focus-loss" => options.pause_on_focus_loss = true,
+                "--speed" => {
+                    options.speed = parse(arg, &value("a speed factor"), 0.1..=16.0);
+                }
+                "--fast-forward" => {
+                    options.fast_forward = Some(parse(arg, &value("a speed factor"), 0.0..=64.0));
+                }
+                "--slow-motion" => {
+                    options.slow_motion = Some(parse(arg, &value("a speed factor"), 0.05..=1.0));
+                }
+                "--rewind-budget" => {
+                    options.rewind_budget = Some(parse(arg, &value("a size in MiB"), 0..=4096));
+                }
+                "--rewind-interval" => {
+                    options.rewind_interval =
+                        Some(parse(arg, &value("a number of frames"), 1..=600));
+                }
+                "--rewind-speed" => {
+                    options.rewind_speed = Some(parse(arg, &value("a speed factor"), 0.1..=16.0));
+                }
+                "--palette" => {
+                    let name = value("a palette name or file");
+                    options.palette = Some(
+                        Palette::find(&name)
+                            .unwrap_or_else(|e| fail(format!("failed to load palette: {}", e))),
+                    );
+                }
+                "--accurate-ppu" => options.renderer = Renderer::Fifo,
+                "--load-state" => options.load_state = Some(value("a save state file name")),
+                "--save-state" => options.save_state = Some(value("a save state file name")),
+                "--headless" => options.headless = true,
+                "--frames" => options.frames = Some(parse(arg, &value("a number of frames"), ..)),
+                "--screenshot" => options.screenshot = Some(value("a PNG file name")),
+                "--audio-sync" => options.audio_sync = true,
+                "--latency" => {
+                    options.latency = Some(parse(arg, &value("a value in milliseconds"), 1..));
+                }
+                "--record" => options.record = Some(value("a WAV file name")),
+                "--record-channels" => options.record_channels = true,
+                "--bind" => match KeyBindings::parse(&value("a <key>=<button> binding")) {
+                    Ok(binding) => options.key_binds.push(binding),
+                    Err(e) => usage_error(e),
+                },
+                "--pad-bind" => match Controllers::parse(&value("a <pad button>=<button> binding"))
+                {
+                    Ok(binding) => options.pad_binds.push(binding),
+                    Err(e) => usage_error(e),
+                },
+                "--deadzone" => options.deadzone = Some(parse(arg, &value("a value"), 0..)),
+                "--serial-stdout" => options.serial = Some(SerialOption::Stdout),
+                "--printer" => options.serial = Some(SerialOption::Printer),
+                "--link-listen" => {
+                    let addr = value("a <host>:<port> or unix:<path> address");
+                    options.serial = Some(SerialOption::Listen(addr));
+                }
+                "--link-connect" => {
+                    let addr = value("a <host>:<port> or unix:<path> address");
+                    options.serial = Some(SerialOption::Connect(addr));
+                }
+                opt if opt.starts_with('-') => usage_error(format!("Unknown option: {}", opt)),
+                rom if options.rom.is_none() => options.rom = Some(rom.to_string()),
+                extra => usage_error(format!("Unexpected argument: {}", extra)),
+            }
+        }
+        options
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("The file name argument is required.");
-        exit(1);
+// Parses the value of `opt`, which has to be within `range`
+fn parse<T, R>(opt: &str, value: &str, range: R) -> T
+where
+    T: FromStr + PartialOrd,
+    R: RangeBounds<T>,
+{
+    match value.parse() {
+        Ok(v) if range.contains(&v) => v,
+        _ => usage_error(format!("Invalid value for {}: {}", opt, value)),
     }
+}
+
+fn info(args: &[String]) {
+    let [rom] = args else {
+        usage_error("info requires exactly one ROM file.");
+    };
+    let rom = read_file(rom);
+    let header = Header::parse(&rom).unwrap_or_else(|e| fail(e));
 
-    let cartridge_raw = file2vec(&args[1]);
-    let bootrom = bootrom::Bootrom::new(cartridge_raw.into());
+    let checksum = Header::compute_header_checksum(&rom);
+    let global = rom
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 0x14e && i != 0x14f)
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+    let status = |ok: bool| if ok { "ok" } else { "mismatch" };
 
-    let mut gameboy = gameboy::Gameboy::new(bootrom);
-    gameboy.run();
+    println!("Title:           {}", header.title);
+    println!(
+        "Cartridge type:  {:#04x} ({})",
+        header.cartridge_type,
+        header.mbc_name()
+    );
+    println!("ROM size:        {} KiB", header.rom_size / 1024);
+    println!("RAM size:        {} KiB", header.ram_size / 1024);
+    println!("CGB flag:        {:#04x}", header.cgb_flag);
+    println!("SGB flag:        {:#04x}", header.sgb_flag);
+    println!(
+        "Destination:     {}",
+        if header.destination == 0 {
+            "Japan"
+        } else {
+            "Overseas"
+        }
+    );
+    println!("Licensee:        {:#04x}", header.licensee);
+    println!("Version:         {}", header.version);
+    println!(
+        "Header checksum: {:#04x} ({})",
+        header.header_checksum,
+        status(checksum == header.header_checksum)
+    );
+    println!(
+        "Global checksum: {:#06x} ({})",
+        header.global_checksum,
+        status(global == header.global_checksum)
+    );
+}
+
+fn run(args: &[String]) {
+    let options = Options::parse(args);
+    let Some(rom) = &options.rom else {
+        usage_error("run requires a ROM file.");
+    };
+
+    let config = match options.config.clone().or_else(Config::default_path) {
+        Some(path) => Config::load(&path).unwrap_or_else(|e| fail(e)),
+        None => Config::default(),
+    };
+
+    let cartridge = Cartridge::new(read_file(rom).into())
+        .unwrap_or_else(|e| fail(format!("failed to load {}: {}", rom, e)));
+    let bootrom = options
+        .bootrom
+        .as_deref()
+        .map(|fname| Bootrom::new(read_file(fname).into()));
+
+    let mut gameboy = Gameboy::new(options.model, bootrom);
+    gameboy.insert_cartridge(cartridge);
+    gameboy.set_renderer(options.renderer);
+    let palette = options.palette.or_else(|| {
+        let name = config.palette.as_deref()?;
+        Some(Palette::find(name).unwrap_or_else(|e| fail(format!("failed to load palette: {}", e))))
+    });
+    if let Some(palette) = palette {
+        gameboy.set_palette(palette);
+    }
+    match options.serial {
+        Some(SerialOption::Stdout) => gameboy.set_serial_endpoint(Box::new(serial::Stdout)),
+        Some(SerialOption::Printer) => {
+            let mut printer = Printer::new();
+            printer.set_output_dir(config.captures_dir().to_path_buf());
+            gameboy.set_serial_endpoint(Box::new(printer));
+        }
+        Some(SerialOption::Listen(addr)) => match LinkCable::listen(&addr) {
+            Ok(link) => gameboy.set_serial_endpoint(Box::new(link)),
+            Err(e) => fail(format!("failed to set up the link cable: {}", e)),
+        },
+        Some(SerialOption::Connect(addr)) => match LinkCable::connect(&addr) {
+            Ok(link) => gameboy.set_serial_endpoint(Box::new(link)),
+            Err(e) => fail(format!("failed to set up the link cable: {}", e)),
+        },
+        None => (),
+    }
+    if let Some(fname) = &options.record {
+        if let Err(e) = gameboy.start_recording(Path::new(fname), options.record_channels) {
+            fail(format!("failed to start recording: {}", e));
+        }
+    }
+
+    if let Some(fname) = &options.load_state {
+        if let Err(e) = gameboy.load_state(&read_file(fname)) {
+            fail(format!("failed to load {}: {}", fname, e));
+        }
+    }
+
+    if options.headless {
+        let mut frames = 0;
+        while options.frames.is_none_or(|n| frames < n) {
+            gameboy.run_frame();
+            gameboy.flush_audio();
+            let output = gameboy.serial_output();
+            if !output.is_empty() {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(&output);
+                let _ = stdout.flush();
+            }
+            frames += 1;
+        }
+        if let Some(fname) = &options.screenshot {
+            let (width, height) = (LCD_WIDTH as u32, LCD_HEIGHT as u32);
+            if let Err(e) = png::write_rgb(fname, width, height, gameboy.frame_buffer()) {
+                fail(format!("failed to write screenshot: {}", e));
+            }
+        }
+        if let Some(fname) = &options.save_state {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            if let Err(e) = fs::write(fname, gameboy.save_bess(now)) {
+                fail(format!("failed to write {}: {}", fname, e));
+            }
+        }
+        return;
+    }
+
+    let mut keys = KeyBindings::new();
+    for &(key, button) in config.keys.iter().chain(&options.key_binds) {
+        keys.bind(key, button);
+    }
+    if let Err(e) = config.hotkeys.check(&keys) {
+        fail(e);
+    }
+
+    let scale = options.scale.or(config.scale).unwrap_or(4);
+    let vsync = options.vsync || config.vsync == Some(true);
+    let mut frontend = Frontend::new(gameboy, scale, vsync);
+    frontend.set_fullscreen(options.fullscreen || config.fullscreen == Some(true));
+    frontend.set_integer_scale(options.integer_scale || config.integer_scale == Some(true));
+    frontend.set_pause_on_focus_loss(
+        options.pause_on_focus_loss || config.pause_on_focus_loss == Some(true),
+    );
+    frontend.set_speed(options.speed);
+    if let Some(speed) = options.fast_forward.or(config.fast_forward) {
+        frontend.set_fast_forward(speed);
+    }
+    if let Some(speed) = options.slow_motion.or(config.slow_motion) {
+        frontend.set_slow_motion(speed);
+    }
+    frontend.set_rewind(
+        options
+            .rewind_interval
+            .or(config.rewind_interval)
+            .unwrap_or(2),
+        options.rewind_budget.or(config.rewind_budget).unwrap_or(64),
+    );
+    if let Some(speed) = options.rewind_speed.or(config.rewind_speed) {
+        frontend.set_rewind_speed(speed);
+    }
+    frontend.set_record_channels(options.record_channels);
+    frontend.set_audio_sync(options.audio_sync);
+    if let Some(latency) = options.latency.or(config.latency) {
+        frontend.set_audio_latency(latency);
+    }
+    *frontend.key_bindings_mut() = keys;
+    for &(pad_button, button) in config.pad.iter().chain(&options.pad_binds) {
+        frontend.controllers_mut().bind(pad_button, button);
+    }
+    if let Some(deadzone) = options.deadzone.or(config.deadzone) {
+        frontend.controllers_mut().set_deadzone(deadzone);
+    }
+    if let Some(name) = Path::new(rom).file_stem() {
+        frontend.set_rom_name(&name.to_string_lossy());
+    }
+    frontend.set_config(config);
+    frontend.run();
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("run") => run(&args[2..]),
+        Some("info") => info(&args[2..]),
+        Some("help" | "--help" | "-h") => println!("{}", USAGE),
+        Some(cmd) => usage_error(format!("Unknown command: {}", cmd)),
+        None => usage_error("A command is required."),
+    }
 }
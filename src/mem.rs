@@ -1,49 +1,162 @@
-use crate::{bootrom::Bootrom, hram::Hram, ppu::Ppu, wram::Wram};
+use crate::{
+    apu::Apu,
+    bootrom::Bootrom,
+    cartridge::Cartridge,
+    hram::Hram,
+    interrupts::Interrupts,
+    joypad::Joypad,
+    ppu::Ppu,
+    serial::Serial,
+    state::{Reader, Writer},
+    timer::Timer,
+    wram::Wram,
+};
+use alloc::string::String;
+
+// Save state chunks written by `Memory::save()`, the cartridge's only with one inserted
+pub(crate) const CHUNKS: [&[u8; 4]; 7] = [
+    b"MEM ", b"PPU ", b"APU ", b"TIMR", b"SERL", b"JOYP", b"INTR",
+];
+pub(crate) const CARTRIDGE_CHUNK: &[u8; 4] = b"CART";
 
 pub struct Memory {
-    bootrom: Bootrom,
-    wram: Wram,
-    hram: Hram,
+    bootrom: Option<Bootrom>,
+    pub cartridge: Option<Cartridge>,
+    pub(crate) wram: Wram,
+    pub(crate) hram: Hram,
     pub ppu: Ppu,
+    pub serial: Serial,
+    pub timer: Timer,
+    pub apu: Apu,
+    pub joypad: Joypad,
+    pub interrupts: Interrupts,
 }
 
 impl Memory {
-    pub fn new(bootrom: Bootrom) -> Self {
+    pub fn new(bootrom: Option<Bootrom>) -> Self {
         Self {
             bootrom,
+            cartridge: None,
             wram: Wram::new(),
             hram: Hram::new(),
             ppu: Ppu::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
+            apu: Apu::new(),
+            joypad: Joypad::new(),
+            interrupts: Interrupts::new(),
+        }
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.chunk(b"MEM ", |w| {
+            self.wram.save(w);
+            self.hram.save(w);
+            w.bool(self.bootrom_active());
+        });
+        w.chunk(b"PPU ", |w| self.ppu.save(w));
+        w.chunk(b"APU ", |w| self.apu.save(w));
+        w.chunk(b"TIMR", |w| self.timer.save(w));
+        w.chunk(b"SERL", |w| self.serial.save(w));
+        w.chunk(b"JOYP", |w| self.joypad.save(w));
+        w.chunk(b"INTR", |w| self.interrupts.save(w));
+        if let Some(cartridge) = &self.cartridge {
+            w.chunk(CARTRIDGE_CHUNK, |w| cartridge.save(w));
+        }
+    }
+
+    pub(crate) fn bootrom_active(&self) -> bool {
+        self.bootrom
+            .as_ref()
+            .is_some_and(|bootrom| bootrom.active())
+    }
+
+    pub(crate) fn set_bootrom_active(&mut self, active: bool) -> Result<(), String> {
+        match &mut self.bootrom {
+            Some(bootrom) => bootrom.set_active(active),
+            None if active => {
+                return Err("the save state was made while running the boot ROM".into())
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    // Chunks for other parts of the system are ignored
+    pub(crate) fn load_chunk(&mut self, tag: &[u8; 4], r: &mut Reader) -> Result<(), String> {
+        match tag {
+            b"MEM " => {
+                self.wram.load(r)?;
+                self.hram.load(r)?;
+                self.set_bootrom_active(r.bool()?)
+            }
+            b"PPU " => self.ppu.load(r),
+            b"APU " => self.apu.load(r),
+            b"TIMR" => self.timer.load(r),
+            b"SERL" => self.serial.load(r),
+            b"JOYP" => self.joypad.load(r),
+            b"INTR" => self.interrupts.load(r),
+            b"CART" => match &mut self.cartridge {
+                Some(cartridge) => cartridge.load(r),
+                None => Ok(()),
+            },
+            _ => Ok(()),
         }
     }
 
+    // Reads as 0xff with no cartridge inserted
+    fn read_cartridge(&self, addr: u16) -> u8 {
+        self.cartridge
+            .as_ref()
+            .map_or(0xff, |cartridge| cartridge.read(addr))
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x00ff => {
-                if self.bootrom.active() {
-                    self.bootrom.read(addr)
-                } else {
-                    0xff
-                }
-            }
+            0x0000..=0x00ff => match &self.bootrom {
+                Some(bootrom) if bootrom.active() => bootrom.read(addr),
+                _ => self.read_cartridge(addr),
+            },
+            0x0100..=0x7fff | 0xa000..=0xbfff => self.read_cartridge(addr),
             0x8000..=0x9fff => self.ppu.read(addr),
-            0x0c00..=0xfdff => self.wram.read(addr),
+            0xc000..=0xfdff => self.wram.read(addr),
             0xfe00..=0xfe9f => self.ppu.read(addr),
+            0xff00 => self.joypad.read(addr),
+            0xff01..=0xff02 => self.serial.read(addr),
+            0xff04..=0xff07 => self.timer.read(addr),
+            0xff0f => self.interrupts.read(addr),
+            0xff10..=0xff3f => self.apu.read(addr),
             0xff40..=0xff4b => self.ppu.read(addr),
             0xff80..=0xfffe => self.hram.read(addr),
+            0xffff => self.interrupts.read(addr),
             _ => 0xff,
         }
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
         match addr {
+            0x0000..=0x7fff | 0xa000..=0xbfff => {
+                if let Some(cartridge) = &mut self.cartridge {
+                    cartridge.write(addr, data);
+                }
+            }
             0x8000..=0x9fff => self.ppu.write(addr, data),
             0xc000..=0xfdff => self.wram.write(addr, data),
             0xfe00..=0xfe9f => self.ppu.write(addr, data),
+            0xff00 => self.joypad.write(addr, data),
+            0xff01..=0xff02 => self.serial.write(addr, data),
+            0xff04..=0xff07 => self.timer.write(addr, data),
+            0xff0f => self.interrupts.write(addr, data),
+            0xff10..=0xff3f => self.apu.write(addr, data),
             0xff40..=0xff4b => self.ppu.write(addr, data),
-            0xff50 => self.bootrom.write(addr, data),
+            0xff50 => {
+                if let Some(bootrom) = &mut self.bootrom {
+                    bootrom.write(addr, data);
+                }
+            }
             0xff80..=0xfffe => self.hram.write(addr, data),
-            _=>(),
+            0xffff => self.interrupts.write(addr, data),
+            _ => (),
         }
     }
 }
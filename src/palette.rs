@@ -0,0 +1,128 @@
+use alloc::{format, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use std::fs;
+
+pub type Rgb = [u8; 3];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Layer {
+    Bg = 0,
+    Obp0 = 1,
+    Obp1 = 2,
+}
+
+// Maps the 2-bit shades of each layer to RGB, lightest shade first
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Palette {
+    pub bg: [Rgb; 4],
+    pub obp0: [Rgb; 4],
+    pub obp1: [Rgb; 4],
+}
+
+impl Palette {
+    pub const GRAYSCALE: Self = Self::uniform([
+        [0xff, 0xff, 0xff],
+        [0xaa, 0xaa, 0xaa],
+        [0x55, 0x55, 0x55],
+        [0x00, 0x00, 0x00],
+    ]);
+    pub const CLASSIC_GREEN: Self = Self::uniform([
+        [0x9b, 0xbc, 0x0f],
+        [0x8b, 0xac, 0x0f],
+        [0x30, 0x62, 0x30],
+        [0x0f, 0x38, 0x0f],
+    ]);
+    pub const POCKET: Self = Self::uniform([
+        [0xc4, 0xcf, 0xa1],
+        [0x8b, 0x95, 0x6d],
+        [0x4d, 0x53, 0x3c],
+        [0x1f, 0x1f, 0x1f],
+    ]);
+    pub const LIGHT: Self = Self::uniform([
+        [0x00, 0xb5, 0x81],
+        [0x00, 0x9a, 0x71],
+        [0x00, 0x69, 0x4a],
+        [0x00, 0x4f, 0x3b],
+    ]);
+
+    pub const BUILTIN: [(&'static str, Self); 4] = [
+        ("grayscale", Self::GRAYSCALE),
+        ("green", Self::CLASSIC_GREEN),
+        ("pocket", Self::POCKET),
+        ("light", Self::LIGHT),
+    ];
+
+    pub const fn uniform(colors: [Rgb; 4]) -> Self {
+        Self {
+            bg: colors,
+            obp0: colors,
+            obp1: colors,
+        }
+    }
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        Self::BUILTIN
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, palette)| palette)
+    }
+
+    // Parses 4 colors shared by all layers, or 12 colors for BG, OBP0 and OBP1
+    // in that order, written as hex triplets like `#9bbc0f`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let colors = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|e| !e.is_empty())
+            .map(Self::parse_color)
+            .collect::<Result<Vec<Rgb>, String>>()?;
+
+        let layer = |i: usize| [colors[i], colors[i + 1], colors[i + 2], colors[i + 3]];
+        match colors.len() {
+            4 => Ok(Self::uniform(layer(0))),
+            12 => Ok(Self {
+                bg: layer(0),
+                obp0: layer(4),
+                obp1: layer(8),
+            }),
+            n => Err(format!("expected 4 or 12 colors, found {}", n)),
+        }
+    }
+
+    fn parse_color(s: &str) -> Result<Rgb, String> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        match u32::from_str_radix(hex, 16) {
+            Ok(v) if hex.len() == 6 => Ok([(v >> 16) as u8, (v >> 8) as u8, v as u8]),
+            _ => Err(format!("invalid color `{}`", s)),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load(fname: &str) -> Result<Self, String> {
+        let s = fs::read_to_string(fname).map_err(|e| format!("{}: {}", fname, e))?;
+        Self::parse(&s).map_err(|e| format!("{}: {}", fname, e))
+    }
+
+    // A built-in palette name or a palette file
+    #[cfg(feature = "std")]
+    pub fn find(name: &str) -> Result<Self, String> {
+        match Self::builtin(name) {
+            Some(palette) => Ok(palette),
+            None => Self::load(name),
+        }
+    }
+
+    pub fn rgb(&self, layer: Layer, shade: u8) -> Rgb {
+        let colors = match layer {
+            Layer::Bg => &self.bg,
+            Layer::Obp0 => &self.obp0,
+            Layer::Obp1 => &self.obp1,
+        };
+        colors[(shade & 0b11) as usize]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::GRAYSCALE
+    }
+}
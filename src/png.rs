@@ -0,0 +1,166 @@
+use std::{fs, io, path::Path};
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 > 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// Wraps `data` in a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Writes an 8-bit grayscale image, `pixels` holds `width` * `height` bytes row by row
+pub fn write_grayscale<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+) -> io::Result<()> {
+    write(path, width, height, 0, pixels)
+}
+
+// Writes an 8-bit RGB image, `pixels` holds `width` * `height` RGB triples row by row
+pub fn write_rgb<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+) -> io::Result<()> {
+    write(path, width, height, 2, pixels)
+}
+
+fn write<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    color_type: u8,
+    pixels: &[u8],
+) -> io::Result<()> {
+    fs::write(path, encode(width, height, color_type, pixels))
+}
+
+fn encode(width: u32, height: u32, color_type: u8, pixels: &[u8]) -> Vec<u8> {
+    let stride = pixels.len() / height.max(1) as usize;
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+
+    let mut ihdr = vec![];
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, color_type, 0, 0, 0]); // 8-bit, no interlacing
+    chunk(&mut png, b"IHDR", &ihdr);
+
+    // Every scanline starts with filter type 0
+    let mut raw = Vec::with_capacity(pixels.len() + height as usize);
+    for row in pixels.chunks_exact(stride) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn chunks_and_zlib_stream() {
+        let pixels = [0x00, 0x40, 0x80, 0xc0, 0xff, 0x10];
+        let png = encode(3, 2, 0, &pixels);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        let mut chunks = vec![];
+        let mut pos = 8;
+        while pos < png.len() {
+            let len = u32_at(&png, pos) as usize;
+            let body = &png[pos + 4..pos + 8 + len];
+            assert_eq!(u32_at(&png, pos + 8 + len), crc32(body));
+            chunks.push((&body[..4], &body[4..]));
+            pos += 12 + len;
+        }
+        assert_eq!(pos, png.len());
+        let kinds: Vec<_> = chunks.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        // IEND has no data, so its CRC is always the same
+        assert_eq!(u32_at(&png, png.len() - 4), 0xae426082);
+
+        let ihdr = chunks[0].1;
+        assert_eq!((u32_at(ihdr, 0), u32_at(ihdr, 4)), (3, 2));
+        assert_eq!(ihdr[8..], [8, 0, 0, 0, 0]);
+
+        // A single stored block of the filtered rows, then the Adler-32 of them
+        let raw = [0, 0x00, 0x40, 0x80, 0, 0xc0, 0xff, 0x10];
+        let zlib = chunks[1].1;
+        assert_eq!(zlib[..2], [0x78, 0x01]);
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        assert_eq!(zlib[2..7], [1, 8, 0, !8, 0xff]);
+        assert_eq!(zlib[7..15], raw);
+        assert_eq!(u32_at(zlib, 15), adler32(&raw));
+        assert_eq!(zlib.len(), 19);
+    }
+
+    #[test]
+    fn long_data_is_split_into_blocks() {
+        let data = vec![7; 0x10000];
+        let zlib = zlib_stored(&data);
+        // Not the last block, 0xffff bytes
+        assert_eq!(zlib[2..7], [0, 0xff, 0xff, 0, 0]);
+        let second = 7 + 0xffff;
+        assert_eq!(zlib[second..second + 5], [1, 1, 0, 0xfe, 0xff]);
+        assert_eq!(u32_at(&zlib, zlib.len() - 4), adler32(&data));
+    }
+}
@@ -1,5 +1,17 @@
-use crate::constants::*;
-use std::iter;
+use crate::{
+    constants::*,
+    palette::{Layer, Palette},
+    state::{Reader, Writer},
+};
+use alloc::{boxed::Box, format, string::String};
+
+mod fifo;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Renderer {
+    Scanline, // Draws a whole line at the end of mode 3
+    Fifo,     // Emulates the pixel FIFO, so mode 3 length and mid-line writes are accurate
+}
 
 #[derive(Copy, Clone, PartialEq, Eq)]
 enum Mode {
@@ -25,7 +37,12 @@ pub struct Ppu {
     vram: Box<[u8; 0x2000]>,
     oam: Box<[u8; 0xa0]>,
     cycles: u8,
-    buffer: Box<[u8; LCD_PIXELS * 4]>,
+    first_line: bool, // The first line after enabling the LCD starts in HBlank
+    skip_frame: bool, // The first frame after enabling the LCD is not displayed
+    renderer: Renderer,
+    fifo: fifo::Fifo,
+    palette: Palette,
+    buffer: Box<[u8; LCD_PIXELS * 3]>, // RGB24
 }
 
 impl Ppu {
@@ -46,7 +63,12 @@ impl Ppu {
             vram: Box::new([0; 0x2000]),
             oam: Box::new([0; 0xa0]),
             cycles: 20,
-            buffer: Box::new([0; LCD_PIXELS * 4]),
+            first_line: false,
+            skip_frame: false,
+            renderer: Renderer::Scanline,
+            fifo: fifo::Fifo::default(),
+            palette: Palette::default(),
+            buffer: Box::new([0xff; LCD_PIXELS * 3]),
         }
     }
 
@@ -94,7 +116,14 @@ impl Ppu {
                     self.oam[addr as usize & 0xff] = data;
                 }
             }
-            0xff40 => self.lcdc = data,
+            0xff40 => {
+                if self.lcdc & PPU_ENABLE > 0 && data & PPU_ENABLE == 0 {
+                    self.disable();
+                } else if self.lcdc & PPU_ENABLE == 0 && data & PPU_ENABLE > 0 {
+                    self.enable();
+                }
+                self.lcdc = data;
+            }
             0xff41 => self.stat = (self.stat & LYC_EQ_LY) | (data & 0xF8),
             0xff42 => self.scy = data,
             0xff43 => self.scx = data,
@@ -136,15 +165,174 @@ impl Ppu {
         }
     }
 
-    pub fn pixel_buffer(&self) -> Box<[u8]> {
-        self.buffer
-            .iter()
-            .flat_map(|&e| iter::repeat(e).take(3))
-            .collect::<Box<[u8]>>()
+    fn disable(&mut self) {
+        self.ly = 0;
+        self.mode = Mode::HBlank;
+        let white = self.palette.rgb(Layer::Bg, 0);
+        for pixel in self.buffer.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&white);
+        }
+    }
+
+    fn enable(&mut self) {
+        // Line 0 is one M-cycle shorter and has no OAM scan after enabling the LCD
+        self.ly = 0;
+        self.mode = Mode::HBlank;
+        self.cycles = 19;
+        self.first_line = true;
+        self.skip_frame = true;
+        self.fifo.new_frame();
+        self.check_lyc_eq_ly();
+    }
+
+    // The renderer and palette are settings rather than state and are left alone
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.u8(self.mode as u8);
+        for v in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx,
+        ] {
+            w.u8(v);
+        }
+        w.bytes(self.vram.as_slice());
+        w.bytes(self.oam.as_slice());
+        w.u8(self.cycles);
+        w.bool(self.first_line);
+        w.bool(self.skip_frame);
+        self.fifo.save(w);
+        w.bytes(self.buffer.as_slice());
+    }
+
+    pub(crate) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.mode = match r.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OAMScan,
+            3 => Mode::Drawing,
+            mode => return Err(format!("invalid PPU mode {}", mode)),
+        };
+        for v in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            *v = r.u8()?;
+        }
+        r.fill(self.vram.as_mut_slice())?;
+        r.fill(self.oam.as_mut_slice())?;
+        self.cycles = r.u8()?;
+        self.first_line = r.bool()?;
+        self.skip_frame = r.bool()?;
+        self.fifo.load(r)?;
+        r.fill(self.buffer.as_mut_slice())?;
+
+        // Out of range values would underflow the counters or draw past the frame buffer
+        if !(1..=114).contains(&self.cycles) {
+            return Err(format!("invalid PPU cycle count {}", self.cycles));
+        }
+        let last_line = if self.mode == Mode::VBlank { 153 } else { 143 };
+        if self.ly > last_line {
+            return Err(format!(
+                "invalid LY {} in PPU mode {}",
+                self.ly, self.mode as u8
+            ));
+        }
+        if self.mode == Mode::Drawing && self.fifo.x as usize >= LCD_WIDTH {
+            return Err("invalid pixel FIFO position in mode 3".into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn vram(&self) -> &[u8] {
+        self.vram.as_slice()
+    }
+
+    pub(crate) fn vram_mut(&mut self) -> &mut [u8] {
+        self.vram.as_mut_slice()
+    }
+
+    pub(crate) fn oam(&self) -> &[u8] {
+        self.oam.as_slice()
+    }
+
+    pub(crate) fn oam_mut(&mut self) -> &mut [u8] {
+        self.oam.as_mut_slice()
+    }
+
+    // Takes 0xff40-0xff4b from a BESS state, which has no timing within the
+    // line, so the mode in STAT starts over
+    pub(crate) fn load_bess(&mut self, regs: &[u8]) {
+        self.lcdc = regs[0];
+        self.stat = regs[1] & 0x78;
+        self.scy = regs[2];
+        self.scx = regs[3];
+        self.ly = regs[4].min(153);
+        self.lyc = regs[5];
+        self.bgp = regs[7];
+        self.obp0 = regs[8];
+        self.obp1 = regs[9];
+        self.wy = regs[10];
+        self.wx = regs[11];
+        self.first_line = false;
+        self.skip_frame = false;
+        self.fifo = fifo::Fifo::default();
+        if self.lcdc & PPU_ENABLE == 0 {
+            self.ly = 0;
+            self.mode = Mode::HBlank;
+            self.cycles = 51;
+        } else {
+            // Lines past the screen are always in VBlank, whatever STAT says
+            let mode = if self.ly >= 144 { 1 } else { regs[1] & 0x03 };
+            match mode {
+                0 => {
+                    self.mode = Mode::HBlank;
+                    self.cycles = 51;
+                }
+                1 => {
+                    self.mode = Mode::VBlank;
+                    self.cycles = 114;
+                }
+                2 => {
+                    self.mode = Mode::OAMScan;
+                    self.cycles = 20;
+                }
+                _ => self.start_drawing(),
+            }
+        }
+        self.check_lyc_eq_ly();
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    fn shade(palette: u8, pixel: u8) -> u8 {
+        (palette >> (pixel << 1)) & 0b11
+    }
+
+    fn put_pixel(&mut self, x: usize, layer: Layer, shade: u8) {
+        let i = (x + (self.ly as usize) * LCD_WIDTH) * 3;
+        self.buffer[i..i + 3].copy_from_slice(&self.palette.rgb(layer, shade));
+    }
+
+    pub fn frame_buffer(&self) -> &[u8] {
+        self.buffer.as_slice()
     }
 
     fn render(&mut self) {
-        if self.lcdc & BG_DISPLAY_ENABLE == 0 {
+        if self.skip_frame || self.lcdc & BG_DISPLAY_ENABLE == 0 {
             return;
         }
 
@@ -156,13 +344,15 @@ impl Ppu {
 
             let pixel = self.get_pixel_from_tile(tile_ind, y & 7, x & 7);
 
-            self.buffer[i + (self.ly as usize) * LCD_WIDTH] =
-                match (self.bgp >> (pixel << 1)) & 0b11 {
-                    0b00 => 0xff,
-                    0b01 => 0xaa,
-                    0b10 => 0x55,
-                    _ => 0x00,
-                };
+            self.put_pixel(i, Layer::Bg, Self::shade(self.bgp, pixel));
+        }
+    }
+
+    fn start_drawing(&mut self) {
+        self.mode = Mode::Drawing;
+        self.cycles = 43;
+        if self.renderer == Renderer::Fifo {
+            self.fifo_start_line();
         }
     }
 
@@ -172,6 +362,16 @@ impl Ppu {
             return false;
         }
 
+        if self.mode == Mode::Drawing && self.renderer == Renderer::Fifo {
+            if self.fifo_emu() {
+                // HBlank takes the rest of the 114 M-cycle line after the
+                // 20 of the OAM scan, and at least one
+                self.mode = Mode::HBlank;
+                self.cycles = 94u8.saturating_sub(self.fifo.cycles).max(1);
+            }
+            return false;
+        }
+
         self.cycles -= 1;
         if self.cycles > 0 {
             return false;
@@ -179,18 +379,21 @@ impl Ppu {
 
         let mut ret = false; // Is VSYNC
         match self.mode {
-            Mode::OAMScan => {
-                self.mode = Mode::Drawing;
-                self.cycles = 43;
-            }
+            Mode::OAMScan => self.start_drawing(),
             Mode::Drawing => {
                 self.render();
                 self.mode = Mode::HBlank;
                 self.cycles = 51;
             }
             Mode::HBlank => {
+                if self.first_line {
+                    self.first_line = false;
+                    self.start_drawing();
+                    return false;
+                }
+
                 self.ly = self.ly.wrapping_add(1);
-                if self.ly < 114 {
+                if self.ly < 144 {
                     self.mode = Mode::OAMScan;
                     self.cycles = 20;
                 } else {
@@ -200,14 +403,19 @@ impl Ppu {
                 self.check_lyc_eq_ly();
             }
             Mode::VBlank => {
-                self.ly = self.ly.wrapping_add(1);
-                if self.ly > 153 {
-                    ret = true;
+                if self.ly == 153 {
+                    // LY reads 0 for all but the first M-cycle of line 153
                     self.ly = 0;
+                    self.cycles = 113;
+                } else if self.ly == 0 {
+                    ret = true;
+                    self.skip_frame = false;
+                    self.fifo.new_frame();
                     self.mode = Mode::OAMScan;
                     self.cycles = 20;
                 } else {
-                    self.cycles = 114;
+                    self.ly += 1;
+                    self.cycles = if self.ly == 153 { 1 } else { 114 };
                 }
                 self.check_lyc_eq_ly();
             }
@@ -215,3 +423,90 @@ impl Ppu {
         ret
     }
 }
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn save(ppu: &Ppu) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.chunk(b"PPU ", |w| ppu.save(w));
+        w.finish()
+    }
+
+    fn load(ppu: &mut Ppu, data: &[u8]) -> Result<(), String> {
+        let (_, mut r) = Reader::new(data)?.chunk()?.unwrap();
+        ppu.load(&mut r)
+    }
+
+    // Part way through mode 3 of a line with a sprite on it
+    fn drawing() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.set_renderer(Renderer::Fifo);
+        ppu.oam[..4].copy_from_slice(&[20, 60, 1, 0]);
+        for (i, v) in ppu.vram[..0x20].iter_mut().enumerate() {
+            *v = (i as u8).wrapping_mul(37);
+        }
+        ppu.write(0xff40, PPU_ENABLE | BG_DISPLAY_ENABLE | SPRITE_ENABLE);
+        while !(ppu.ly == 5 && ppu.mode == Mode::Drawing && ppu.fifo.x > 40) {
+            ppu.emu();
+        }
+        ppu
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut ppu = drawing();
+        let data = save(&ppu);
+        let mut loaded = Ppu::new();
+        loaded.set_renderer(Renderer::Fifo);
+        load(&mut loaded, &data).unwrap();
+        assert_eq!(save(&loaded), data);
+
+        for _ in 0..20000 {
+            assert_eq!(ppu.emu(), loaded.emu());
+        }
+        assert!(ppu.mode == loaded.mode && ppu.ly == loaded.ly);
+        assert_eq!(ppu.frame_buffer(), loaded.frame_buffer());
+    }
+
+    #[test]
+    fn bess_lines_past_the_screen_are_vblank() {
+        let mut ppu = Ppu::new();
+        let mut regs = [0; 12];
+        regs[0] = PPU_ENABLE | BG_DISPLAY_ENABLE;
+        regs[1] = 0x03;
+        regs[4] = 150;
+        ppu.load_bess(&regs);
+        assert!(ppu.mode == Mode::VBlank);
+        for _ in 0..20000 {
+            ppu.emu();
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_state() {
+        let corruptions: [fn(&mut Ppu); 5] = [
+            |ppu| ppu.cycles = 0,
+            |ppu| ppu.fifo.cycles = 114,
+            |ppu| ppu.ly = 144,
+            |ppu| {
+                ppu.mode = Mode::VBlank;
+                ppu.ly = 154;
+            },
+            |ppu| ppu.fifo.x = LCD_WIDTH as u8,
+        ];
+        for corrupt in corruptions {
+            let mut ppu = drawing();
+            corrupt(&mut ppu);
+            assert!(load(&mut Ppu::new(), &save(&ppu)).is_err());
+        }
+    }
+}
@@ -0,0 +1,555 @@
+use crate::{
+    constants::*,
+    palette::Layer,
+    ppu::Ppu,
+    state::{Reader, Writer},
+};
+use alloc::string::String;
+
+#[derive(Clone, Copy, Default)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attr: u8,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ObjPixel {
+    color: u8,
+    attr: u8,
+}
+
+#[derive(Default)]
+struct Fetcher {
+    step: u8,
+    tile_x: u8,
+    tile: usize,
+    row: u8,
+    low: u8,
+    high: u8,
+    window: bool,
+    dummy: bool, // The first fetch of each line is thrown away
+}
+
+#[derive(Default)]
+pub(super) struct Fifo {
+    fetcher: Fetcher,
+    bg: [u8; 8], // Stored in reverse order, popped from the end
+    bg_len: usize,
+    obj: [ObjPixel; 8],
+    obj_len: usize,
+    sprites: [Sprite; 10],
+    sprite_count: usize,
+    sprite_fetched: u16,
+    sprite_dots: u8,
+    pub(super) x: u8, // Pixels pushed to the LCD on this line
+    discard: u8,
+    pub(super) cycles: u8, // M-cycles spent in mode 3
+    window_line: u8,
+    window_drawn: bool,
+    wy_triggered: bool,
+}
+
+impl Fifo {
+    pub(super) fn new_frame(&mut self) {
+        self.window_line = 0;
+        self.window_drawn = false;
+        self.wy_triggered = false;
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        let f = &self.fetcher;
+        for v in [f.step, f.tile_x, f.row, f.low, f.high] {
+            w.u8(v);
+        }
+        w.u16(f.tile as u16);
+        w.bool(f.window);
+        w.bool(f.dummy);
+
+        w.bytes(&self.bg);
+        w.u8(self.bg_len as u8);
+        for pixel in &self.obj {
+            w.u8(pixel.color);
+            w.u8(pixel.attr);
+        }
+        w.u8(self.obj_len as u8);
+        for sprite in &self.sprites {
+            w.bytes(&[sprite.y, sprite.x, sprite.tile, sprite.attr]);
+        }
+        w.u8(self.sprite_count as u8);
+        w.u16(self.sprite_fetched);
+        for v in [
+            self.sprite_dots,
+            self.x,
+            self.discard,
+            self.cycles,
+            self.window_line,
+        ] {
+            w.u8(v);
+        }
+        w.bool(self.window_drawn);
+        w.bool(self.wy_triggered);
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        let f = &mut self.fetcher;
+        for v in [
+            &mut f.step,
+            &mut f.tile_x,
+            &mut f.row,
+            &mut f.low,
+            &mut f.high,
+        ] {
+            *v = r.u8()?;
+        }
+        f.tile = r.u16()? as usize;
+        f.window = r.bool()?;
+        f.dummy = r.bool()?;
+
+        r.fill(&mut self.bg)?;
+        self.bg_len = (r.u8()? as usize).min(self.bg.len());
+        for pixel in &mut self.obj {
+            pixel.color = r.u8()?;
+            pixel.attr = r.u8()?;
+        }
+        self.obj_len = (r.u8()? as usize).min(self.obj.len());
+        for sprite in &mut self.sprites {
+            let mut v = [0; 4];
+            r.fill(&mut v)?;
+            [sprite.y, sprite.x, sprite.tile, sprite.attr] = v;
+        }
+        self.sprite_count = (r.u8()? as usize).min(self.sprites.len());
+        self.sprite_fetched = r.u16()?;
+        for v in [
+            &mut self.sprite_dots,
+            &mut self.x,
+            &mut self.discard,
+            &mut self.cycles,
+            &mut self.window_line,
+        ] {
+            *v = r.u8()?;
+        }
+        self.window_drawn = r.bool()?;
+        self.wy_triggered = r.bool()?;
+
+        // Mode 3 can't take up the whole line
+        if self.cycles >= 114 || self.sprite_dots >= 6 || self.x as usize > LCD_WIDTH {
+            return Err("invalid pixel FIFO state".into());
+        }
+        Ok(())
+    }
+}
+
+impl Ppu {
+    pub(super) fn fifo_start_line(&mut self) {
+        if self.ly == self.wy {
+            self.fifo.wy_triggered = true;
+        }
+        if self.fifo.window_drawn {
+            self.fifo.window_line = self.fifo.window_line.wrapping_add(1);
+        }
+
+        self.fifo.fetcher = Fetcher {
+            dummy: true,
+            ..Default::default()
+        };
+        self.fifo.bg_len = 0;
+        self.fifo.obj_len = 0;
+        self.fifo.sprite_dots = 0;
+        self.fifo.sprite_fetched = 0;
+        self.fifo.x = 0;
+        self.fifo.discard = self.scx & 7;
+        self.fifo.cycles = 0;
+        self.fifo.window_drawn = false;
+        self.oam_scan();
+    }
+
+    // Selects up to 10 sprites overlapping the current line, in OAM order
+    fn oam_scan(&mut self) {
+        let height = if self.lcdc & SPRITE_SIZE > 0 { 16 } else { 8 };
+        let line = self.ly.wrapping_add(16);
+
+        self.fifo.sprite_count = 0;
+        for entry in self.oam.chunks_exact(4) {
+            if self.fifo.sprite_count == 10 {
+                break;
+            }
+            let y = entry[0];
+            if line >= y && line < y.wrapping_add(height) {
+                self.fifo.sprites[self.fifo.sprite_count] = Sprite {
+                    y,
+                    x: entry[1],
+                    tile: entry[2],
+                    attr: entry[3],
+                };
+                self.fifo.sprite_count += 1;
+            }
+        }
+    }
+
+    // Advances mode 3 by one M-cycle, returns true when the line is complete
+    pub(super) fn fifo_emu(&mut self) -> bool {
+        self.fifo.cycles += 1;
+        for _ in 0..4 {
+            self.dot();
+            if self.fifo.x as usize == LCD_WIDTH {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn dot(&mut self) {
+        if let Some(i) = self.pending_sprite() {
+            // The BG fetch in progress has to reach its last step before the
+            // sprite is fetched, which makes the stall 6 to 11 dots
+            if self.fifo.fetcher.step < 5 || self.fifo.bg_len == 0 {
+                self.fetch_bg();
+                return;
+            }
+            self.fifo.sprite_dots += 1;
+            if self.fifo.sprite_dots == 6 {
+                self.fifo.sprite_dots = 0;
+                self.fetch_sprite(i);
+            }
+            return;
+        }
+
+        self.push_pixel();
+        self.fetch_bg();
+    }
+
+    fn pending_sprite(&self) -> Option<usize> {
+        if self.lcdc & SPRITE_ENABLE == 0 {
+            return None;
+        }
+        (0..self.fifo.sprite_count)
+            .filter(|&i| self.fifo.sprite_fetched & (1 << i) == 0)
+            .filter(|&i| self.fifo.sprites[i].x as u16 <= self.fifo.x as u16 + 8)
+            .min_by_key(|&i| self.fifo.sprites[i].x)
+    }
+
+    fn window_active(&self) -> bool {
+        self.lcdc & WINDOW_ENABLE > 0
+            && self.lcdc & BG_DISPLAY_ENABLE > 0
+            && self.fifo.wy_triggered
+            && self.fifo.x as u16 + 7 >= self.wx as u16
+    }
+
+    fn fetch_bg(&mut self) {
+        match self.fifo.fetcher.step {
+            1 => {
+                let (tile_map, y, x) = if self.fifo.fetcher.window {
+                    (
+                        self.lcdc & WINDOW_TILE_MAP_SELECT > 0,
+                        self.fifo.window_line,
+                        self.fifo.fetcher.tile_x,
+                    )
+                } else {
+                    (
+                        self.lcdc & BG_TILE_MAP_SELECT > 0,
+                        self.ly.wrapping_add(self.scy),
+                        (self.scx >> 3).wrapping_add(self.fifo.fetcher.tile_x),
+                    )
+                };
+                self.fifo.fetcher.row = y & 7;
+                self.fifo.fetcher.tile =
+                    self.get_tile_idx_from_tile_map(tile_map, y >> 3, x & 0x1f);
+            }
+            3 => self.fifo.fetcher.low = self.vram[self.tile_data_addr()],
+            5 => self.fifo.fetcher.high = self.vram[self.tile_data_addr() + 1],
+            _ => {}
+        }
+
+        let f = &mut self.fifo.fetcher;
+        f.step = f.step.saturating_add(1);
+        if f.step < 6 || self.fifo.bg_len > 0 {
+            return;
+        }
+
+        f.step = 0;
+        if f.dummy {
+            f.dummy = false;
+            return;
+        }
+        for i in 0..8 {
+            // Leftmost pixel ends up last, as the queue is popped from the end
+            self.fifo.bg[i] = (((f.high >> i) & 1) << 1) | ((f.low >> i) & 1);
+        }
+        self.fifo.bg_len = 8;
+        f.tile_x = f.tile_x.wrapping_add(1);
+    }
+
+    fn tile_data_addr(&self) -> usize {
+        ((self.fifo.fetcher.tile << 4) | (self.fifo.fetcher.row as usize * 2)) & 0x1fff
+    }
+
+    fn fetch_sprite(&mut self, i: usize) {
+        self.fifo.sprite_fetched |= 1 << i;
+        let sprite = self.fifo.sprites[i];
+
+        // The size can change after the OAM scan picked a sprite, so the row is
+        // masked to the current height before flipping
+        let tall = self.lcdc & SPRITE_SIZE > 0;
+        let height_mask = if tall { 15 } else { 7 };
+        let mut row = self.ly.wrapping_add(16).wrapping_sub(sprite.y) & height_mask;
+        if sprite.attr & SPRITE_Y_FLIP > 0 {
+            row = height_mask - row;
+        }
+        let tile = if tall {
+            sprite.tile & 0xfe
+        } else {
+            sprite.tile
+        } as usize;
+        let addr = (tile << 4) + row as usize * 2;
+        let (low, high) = (self.vram[addr & 0x1fff], self.vram[(addr + 1) & 0x1fff]);
+
+        // Pixels left of the screen edge are never shifted out
+        let skip = 8u8.saturating_sub(sprite.x) as usize;
+        for p in skip..8 {
+            let bit = if sprite.attr & SPRITE_X_FLIP > 0 {
+                p
+            } else {
+                7 - p
+            };
+            let color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+            let slot = p - skip;
+            if slot >= self.fifo.obj_len {
+                self.fifo.obj[slot] = ObjPixel::default();
+            }
+            if self.fifo.obj[slot].color == 0 {
+                self.fifo.obj[slot] = ObjPixel {
+                    color,
+                    attr: sprite.attr,
+                };
+            }
+        }
+        self.fifo.obj_len = self.fifo.obj_len.max(8 - skip);
+    }
+
+    fn push_pixel(&mut self) {
+        if !self.fifo.fetcher.window && self.window_active() {
+            self.fifo.fetcher = Fetcher {
+                window: true,
+                ..Default::default()
+            };
+            self.fifo.bg_len = 0;
+            self.fifo.window_drawn = true;
+            return;
+        }
+
+        if self.fifo.bg_len == 0 {
+            return;
+        }
+        self.fifo.bg_len -= 1;
+        let bg = self.fifo.bg[self.fifo.bg_len];
+
+        if self.fifo.discard > 0 && !self.fifo.fetcher.window {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let obj = if self.fifo.obj_len > 0 {
+            let obj = self.fifo.obj[0];
+            self.fifo.obj.copy_within(1.., 0);
+            self.fifo.obj_len -= 1;
+            obj
+        } else {
+            ObjPixel::default()
+        };
+
+        // With LCDC.0 off the BG and window are blank, whatever BGP holds
+        let bg_enabled = self.lcdc & BG_DISPLAY_ENABLE > 0;
+        let bg = if bg_enabled { bg } else { 0 };
+        let (layer, shade) = if obj.color > 0
+            && self.lcdc & SPRITE_ENABLE > 0
+            && (obj.attr & SPRITE_BG_PRIORITY == 0 || bg == 0)
+        {
+            if obj.attr & SPRITE_PALETTE > 0 {
+                (Layer::Obp1, Self::shade(self.obp1, obj.color))
+            } else {
+                (Layer::Obp0, Self::shade(self.obp0, obj.color))
+            }
+        } else if bg_enabled {
+            (Layer::Bg, Self::shade(self.bgp, bg))
+        } else {
+            (Layer::Bg, 0)
+        };
+
+        if !self.skip_frame {
+            self.put_pixel(self.fifo.x as usize, layer, shade);
+        }
+        self.fifo.x += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        palette::Palette,
+        ppu::{Mode, Renderer},
+    };
+
+    // Every BG tile is solid color 3
+    fn ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.set_renderer(Renderer::Fifo);
+        ppu.lcdc = PPU_ENABLE | BG_DISPLAY_ENABLE | BG_WINDOW_TILE_DATA_SELECT;
+        ppu.bgp = 0b11_10_01_00;
+        ppu.vram[..0x10].fill(0xff);
+        ppu
+    }
+
+    // Dots from the start of mode 3 to the last pixel pushed
+    fn mode3_dots(ppu: &mut Ppu) -> usize {
+        ppu.start_drawing();
+        let mut dots = 0;
+        while (ppu.fifo.x as usize) < LCD_WIDTH {
+            ppu.dot();
+            dots += 1;
+        }
+        dots
+    }
+
+    fn put_sprite(ppu: &mut Ppu, i: usize, x: u8) {
+        ppu.oam[i * 4..i * 4 + 4].copy_from_slice(&[16, x, 0, 0]);
+    }
+
+    fn shade_at(ppu: &Ppu, x: usize) -> [u8; 3] {
+        let i = (x + ppu.ly as usize * LCD_WIDTH) * 3;
+        ppu.frame_buffer()[i..i + 3].try_into().unwrap()
+    }
+
+    #[test]
+    fn scx_fine_scroll_lengthens_mode3() {
+        let base = mode3_dots(&mut ppu());
+        for scx in 1..8 {
+            let mut ppu = ppu();
+            ppu.scx = scx;
+            assert_eq!(mode3_dots(&mut ppu), base + scx as usize);
+        }
+        // Only the fine scroll matters
+        let mut ppu = ppu();
+        ppu.scx = 16;
+        assert_eq!(mode3_dots(&mut ppu), base);
+    }
+
+    #[test]
+    fn window_restarts_the_fetcher() {
+        let base = mode3_dots(&mut ppu());
+        let mut ppu = ppu();
+        ppu.lcdc |= WINDOW_ENABLE;
+        ppu.wx = 87;
+        // Not triggered before LY reaches WY
+        ppu.wy = 1;
+        assert_eq!(mode3_dots(&mut ppu), base);
+        assert!(!ppu.fifo.window_drawn);
+
+        ppu.ly = 1;
+        assert_eq!(mode3_dots(&mut ppu), base + 6);
+        assert!(ppu.fifo.window_drawn);
+        // Stays triggered for the rest of the frame
+        ppu.ly = 2;
+        assert_eq!(mode3_dots(&mut ppu), base + 6);
+        assert_eq!(ppu.fifo.window_line, 1);
+    }
+
+    #[test]
+    fn sprites_stall_mode3() {
+        let base = mode3_dots(&mut ppu());
+
+        let mut ppu = ppu();
+        ppu.lcdc |= SPRITE_ENABLE;
+        put_sprite(&mut ppu, 0, 88);
+        let one = mode3_dots(&mut ppu);
+        assert_eq!(one, base + 11);
+        // Less of the BG fetch is left to wait for further into a tile
+        for (x, stall) in [(89, 10), (92, 7), (93, 6), (95, 6), (0, 11), (4, 11)] {
+            let mut ppu = self::ppu();
+            ppu.lcdc |= SPRITE_ENABLE;
+            put_sprite(&mut ppu, 0, x);
+            assert_eq!(mode3_dots(&mut ppu), base + stall);
+        }
+
+        for i in 1..12 {
+            put_sprite(&mut ppu, i, 88);
+        }
+        // Only 10 sprites are picked per line
+        let ten = mode3_dots(&mut ppu);
+        assert_eq!(ppu.fifo.sprite_count, 10);
+        assert!(ten > one && ten <= base + 10 * 11);
+
+        // Sprites picked by the OAM scan don't stall while disabled
+        ppu.lcdc &= !SPRITE_ENABLE;
+        assert_eq!(mode3_dots(&mut ppu), base);
+    }
+
+    #[test]
+    fn mid_line_palette_write() {
+        let mut ppu = ppu();
+        ppu.start_drawing();
+        while ppu.fifo.x < 80 {
+            ppu.dot();
+        }
+        ppu.write(0xff47, 0b00_11_11_11);
+        while (ppu.fifo.x as usize) < LCD_WIDTH {
+            ppu.dot();
+        }
+
+        let palette = Palette::default();
+        assert_eq!(shade_at(&ppu, 0), palette.rgb(Layer::Bg, 3));
+        assert_eq!(shade_at(&ppu, 79), palette.rgb(Layer::Bg, 3));
+        assert_eq!(shade_at(&ppu, 80), palette.rgb(Layer::Bg, 0));
+        assert_eq!(shade_at(&ppu, LCD_WIDTH - 1), palette.rgb(Layer::Bg, 0));
+    }
+
+    #[test]
+    fn bg_disabled_is_blank_whatever_the_palette() {
+        let mut ppu = ppu();
+        ppu.lcdc &= !BG_DISPLAY_ENABLE;
+        ppu.bgp = 0xff;
+        mode3_dots(&mut ppu);
+        let blank = Palette::default().rgb(Layer::Bg, 0);
+        assert!((0..LCD_WIDTH).all(|x| shade_at(&ppu, x) == blank));
+    }
+
+    #[test]
+    fn hblank_takes_the_rest_of_the_line() {
+        let mut ppu = ppu();
+        ppu.start_drawing();
+        while ppu.mode == Mode::Drawing {
+            ppu.emu();
+        }
+        assert_eq!(ppu.cycles + ppu.fifo.cycles, 94);
+
+        // A mode 3 running past the line budget still gets one HBlank M-cycle
+        ppu.start_drawing();
+        ppu.fifo.cycles = 100;
+        while ppu.mode == Mode::Drawing {
+            ppu.emu();
+        }
+        assert_eq!(ppu.cycles, 1);
+    }
+
+    #[test]
+    fn flipped_sprite_fetched_after_switching_to_8x8() {
+        let mut ppu = Ppu::new();
+        ppu.lcdc = PPU_ENABLE | SPRITE_ENABLE;
+        // Picked by an 8x16 OAM scan, 10 rows into the sprite
+        ppu.ly = 10;
+        ppu.fifo.sprites[0] = Sprite {
+            y: 16,
+            x: 8,
+            tile: 0,
+            attr: SPRITE_Y_FLIP,
+        };
+        ppu.fifo.sprite_count = 1;
+        // Row 10 of an 8x8 sprite wraps to row 2, flipped to row 5
+        ppu.vram[5 * 2] = 0xff;
+        ppu.fetch_sprite(0);
+        assert_eq!(ppu.fifo.obj_len, 8);
+        assert!(ppu.fifo.obj.iter().all(|pixel| pixel.color == 1));
+    }
+}
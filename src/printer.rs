@@ -0,0 +1,383 @@
+use crate::{png, serial::SerialEndpoint};
+use std::{path::PathBuf, time};
+
+const WIDTH: usize = 160;
+const TILE_ROW_BYTES: usize = WIDTH / 8 * 16; // One row of 20 tiles
+const BUFFER_SIZE: usize = 0x2000;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_BREAK: u8 = 0x08;
+const CMD_STATUS: u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_PRINTING: u8 = 1 << 1;
+const STATUS_DATA_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+
+// Status inquiries answered as busy after a print command
+const PRINT_POLLS: u8 = 8;
+
+#[derive(Clone, Copy)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+// Game Boy Printer, every finished sheet is written to a PNG file
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16, // Sum of the packet so far
+    received: u16, // Checksum sent by the Game Boy
+    status: u8,
+    busy: u8, // Status inquiries left until printing finishes
+    buffer: Vec<u8>,
+    sheet: Vec<u8>, // Grayscale strips printed without a margin in between
+    sheets: u32,
+    dir: PathBuf, // Where printed sheets are saved
+}
+
+impl Printer {
+    pub fn new() -> Self {
+        Self {
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: vec![],
+            checksum: 0,
+            received: 0,
+            status: 0,
+            busy: 0,
+            buffer: vec![],
+            sheet: vec![],
+            sheets: 0,
+            dir: PathBuf::from("."),
+        }
+    }
+
+    pub fn set_output_dir(&mut self, dir: PathBuf) {
+        self.dir = dir;
+    }
+
+    // Expands the run-length encoding used by compressed data packets
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut bytes = data.iter();
+        while let Some(&n) = bytes.next() {
+            if n & 0x80 > 0 {
+                let Some(&byte) = bytes.next() else { break };
+                out.extend(std::iter::repeat_n(byte, (n & 0x7f) as usize + 2));
+            } else {
+                out.extend(bytes.by_ref().take(n as usize + 1));
+            }
+        }
+        out
+    }
+
+    fn process(&mut self) {
+        if self.checksum != self.received {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy = 0;
+            }
+            CMD_DATA if self.data.is_empty() => self.status |= STATUS_DATA_FULL,
+            CMD_DATA => {
+                let data = if self.compressed {
+                    Self::decompress(&self.data)
+                } else {
+                    std::mem::take(&mut self.data)
+                };
+                let len = data.len().min(BUFFER_SIZE - self.buffer.len());
+                self.buffer.extend_from_slice(&data[..len]);
+                self.status |= STATUS_UNPROCESSED;
+            }
+            CMD_PRINT if self.data.len() == 4 => {
+                let (margins, palette, exposure) = (self.data[1], self.data[2], self.data[3]);
+                self.print(margins, palette, exposure);
+                self.status &= !(STATUS_DATA_FULL | STATUS_UNPROCESSED);
+                self.status |= STATUS_PRINTING;
+                self.busy = PRINT_POLLS;
+            }
+            CMD_STATUS if self.busy > 0 => {
+                self.busy -= 1;
+                if self.busy == 0 {
+                    self.status &= !STATUS_PRINTING;
+                }
+            }
+            CMD_BREAK => {
+                self.buffer.clear();
+                self.status &= !(STATUS_DATA_FULL | STATUS_UNPROCESSED);
+            }
+            _ => (),
+        }
+    }
+
+    // Renders the buffered tiles as a strip, finishing the sheet if a bottom margin follows
+    fn print(&mut self, margins: u8, palette: u8, exposure: u8) {
+        // A palette of 0 is treated as the usual 0xe4 by the printer
+        let palette = if palette == 0 { 0xe4 } else { palette };
+        // Exposure 0x00-0x7f ranges from 25% lighter to 25% darker
+        let darkness = 0.75 + 0.5 * (exposure & 0x7f) as f32 / 127.0;
+        let gray = |color: u8| {
+            let shade = (palette >> (color * 2)) & 0x03;
+            255 - (shade as f32 / 3.0 * darkness * 255.0).min(255.0) as u8
+        };
+
+        for tiles in self.buffer.chunks_exact(TILE_ROW_BYTES) {
+            for y in 0..8 {
+                for x in 0..WIDTH {
+                    let tile = &tiles[x / 8 * 16..];
+                    let bit = 7 - x % 8;
+                    let lo = (tile[y * 2] >> bit) & 1;
+                    let hi = (tile[y * 2 + 1] >> bit) & 1;
+                    self.sheet.push(gray(hi << 1 | lo));
+                }
+            }
+        }
+        self.buffer.clear();
+
+        if margins & 0x0f > 0 {
+            self.finish_sheet();
+        }
+    }
+
+    fn finish_sheet(&mut self) {
+        if self.sheet.is_empty() {
+            return;
+        }
+        let secs = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.sheets += 1;
+        let path = self
+            .dir
+            .join(format!("gemu-print-{}-{}.png", secs, self.sheets));
+        let height = (self.sheet.len() / WIDTH) as u32;
+        match png::write_grayscale(&path, WIDTH as u32, height, &self.sheet) {
+            Ok(()) => eprintln!("printed to {}", path.display()),
+            Err(e) => eprintln!("failed to write {}: {}", path.display(), e),
+        }
+        self.sheet.clear();
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialEndpoint for Printer {
+    fn transfer(&mut self, data: u8) -> u8 {
+        let mut reply = 0x00;
+        let add = |checksum: u16| checksum.wrapping_add(data as u16);
+
+        self.state = match self.state {
+            State::Magic1 if data == 0x88 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if data == 0x33 => State::Command,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = data;
+                self.checksum = data as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = data & 0x01 > 0;
+                self.checksum = add(self.checksum);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = data as u16;
+                self.checksum = add(self.checksum);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (data as u16) << 8;
+                self.checksum = add(self.checksum);
+                self.data.clear();
+                if self.length > 0 {
+                    State::Data
+                } else {
+                    State::ChecksumLow
+                }
+            }
+            State::Data => {
+                self.data.push(data);
+                self.checksum = add(self.checksum);
+                if self.data.len() == self.length as usize {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::ChecksumLow => {
+                self.received = data as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received |= (data as u16) << 8;
+                self.process();
+                State::Alive
+            }
+            State::Alive => {
+                reply = 0x81;
+                State::Status
+            }
+            State::Status => {
+                reply = self.status;
+                State::Magic1
+            }
+        };
+        reply
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.finish_sheet();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // Sends a packet, returns the replies to the two bytes after the checksum
+    fn packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        packet_with_checksum(printer, command, compressed, data, 0)
+    }
+
+    fn packet_with_checksum(
+        printer: &mut Printer,
+        command: u8,
+        compressed: bool,
+        data: &[u8],
+        error: u16,
+    ) -> (u8, u8) {
+        let mut bytes = vec![command, compressed as u8];
+        bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(data);
+        let checksum = bytes
+            .iter()
+            .fold(error, |sum, &b| sum.wrapping_add(b as u16));
+
+        for &byte in [0x88, 0x33].iter().chain(&bytes) {
+            assert_eq!(printer.transfer(byte), 0);
+        }
+        for byte in checksum.to_le_bytes() {
+            assert_eq!(printer.transfer(byte), 0);
+        }
+        (printer.transfer(0), printer.transfer(0))
+    }
+
+    #[test]
+    fn init_and_status() {
+        let mut printer = Printer::new();
+        assert_eq!(packet(&mut printer, CMD_INIT, false, &[]), (0x81, 0));
+        assert_eq!(packet(&mut printer, CMD_STATUS, false, &[]), (0x81, 0));
+    }
+
+    #[test]
+    fn data_is_buffered() {
+        let mut printer = Printer::new();
+        packet(&mut printer, CMD_INIT, false, &[]);
+        let tiles = [0x55; TILE_ROW_BYTES * 2];
+        let (_, status) = packet(&mut printer, CMD_DATA, false, &tiles);
+        assert_eq!(status, STATUS_UNPROCESSED);
+        assert_eq!(printer.buffer, tiles);
+
+        // An empty data packet marks the end of the data
+        let (_, status) = packet(&mut printer, CMD_DATA, false, &[]);
+        assert_eq!(status, STATUS_UNPROCESSED | STATUS_DATA_FULL);
+    }
+
+    #[test]
+    fn compressed_data_is_expanded() {
+        let mut printer = Printer::new();
+        // A run of 3 bytes, then 2 literal bytes
+        packet(
+            &mut printer,
+            CMD_DATA,
+            true,
+            &[0x81, 0xaa, 0x01, 0x12, 0x34],
+        );
+        assert_eq!(printer.buffer, [0xaa, 0xaa, 0xaa, 0x12, 0x34]);
+        // Truncated runs and literals stop at the end of the packet
+        assert_eq!(Printer::decompress(&[0x03, 0x01]), [0x01]);
+        assert!(Printer::decompress(&[0x80]).is_empty());
+    }
+
+    #[test]
+    fn bad_checksum_is_reported() {
+        let mut printer = Printer::new();
+        let (alive, status) = packet_with_checksum(&mut printer, CMD_DATA, false, &[1, 2], 1);
+        assert_eq!((alive, status), (0x81, STATUS_CHECKSUM_ERROR));
+        assert!(printer.buffer.is_empty());
+        // Cleared by the next good packet
+        assert_eq!(packet(&mut printer, CMD_STATUS, false, &[]), (0x81, 0));
+    }
+
+    #[test]
+    fn magic_resyncs() {
+        let mut printer = Printer::new();
+        for byte in [0x00, 0x88, 0x00, 0x12] {
+            printer.transfer(byte);
+        }
+        assert_eq!(packet(&mut printer, CMD_STATUS, false, &[]), (0x81, 0));
+    }
+
+    #[test]
+    fn print_writes_a_sheet() {
+        let dir = std::env::temp_dir().join(format!("gemu-printer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut printer = Printer::new();
+        printer.set_output_dir(dir.clone());
+
+        packet(&mut printer, CMD_INIT, false, &[]);
+        packet(&mut printer, CMD_DATA, false, &[0xff; TILE_ROW_BYTES]);
+        packet(&mut printer, CMD_DATA, false, &[]);
+        // One sheet, no top margin, a bottom margin, default palette and exposure
+        let (_, status) = packet(&mut printer, CMD_PRINT, false, &[1, 0x01, 0xe4, 0x40]);
+        assert_eq!(status, STATUS_PRINTING);
+        assert!(printer.buffer.is_empty() && printer.sheet.is_empty());
+        for _ in 1..PRINT_POLLS {
+            assert_eq!(
+                packet(&mut printer, CMD_STATUS, false, &[]).1,
+                STATUS_PRINTING
+            );
+        }
+        assert_eq!(packet(&mut printer, CMD_STATUS, false, &[]).1, 0);
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let png = fs::read(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+        // 160x8 pixels
+        assert_eq!(png[16..24], [0, 0, 0, 160, 0, 0, 0, 8]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+use crate::{apu::Apu, constants::M_CYCLE_HZ, resampler::Resampler, wav::WavWriter};
+use std::{io, path::Path};
+
+// Records the mixed stereo output, and optionally each channel, to WAV files.
+// Each file has its own fixed-ratio resampler so rate control on the playback
+// side never bends the pitch of a recording.
+pub struct Recorder {
+    mixed: (Resampler, WavWriter),
+    channels: Vec<(Resampler, WavWriter)>,
+}
+
+impl Recorder {
+    // Channel files are named after `path` with a `-ch1` .. `-ch4` suffix
+    pub fn create(path: &Path, rate: u32, per_channel: bool) -> io::Result<Self> {
+        let mixed = (
+            Resampler::new(M_CYCLE_HZ, rate),
+            WavWriter::create(path, 2, rate)?,
+        );
+
+        let mut channels = vec![];
+        if per_channel {
+            let stem = path.with_extension("");
+            for ch in 1..=4 {
+                let path = format!("{}-ch{}.wav", stem.display(), ch);
+                channels.push((
+                    Resampler::new(M_CYCLE_HZ, rate),
+                    WavWriter::create(path, 2, rate)?,
+                ));
+            }
+        }
+        Ok(Self { mixed, channels })
+    }
+
+    // Feeds the resamplers, called once per M-cycle
+    pub fn emu(&mut self, apu: &Apu) {
+        self.mixed.0.push(apu.sample());
+        for ((resampler, _), sample) in self.channels.iter_mut().zip(apu.channel_samples()) {
+            resampler.push(sample);
+        }
+    }
+
+    // Writes out everything buffered so far
+    pub fn write(&mut self) -> io::Result<()> {
+        for (resampler, wav) in std::iter::once(&mut self.mixed).chain(&mut self.channels) {
+            wav.write(resampler.samples())?;
+            resampler.clear();
+        }
+        Ok(())
+    }
+}
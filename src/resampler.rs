@@ -0,0 +1,159 @@
+use std::f64::consts::PI;
+
+const DECIMATION: u32 = 8; // Box filter applied before the sinc interpolation
+const TAPS: usize = 32;
+const PHASES: usize = 256;
+const HIGH_PASS: f32 = 0.999; // Removes the DC offset of the DACs
+
+// Band-limited stereo resampler with an adjustable ratio for dynamic rate control
+pub struct Resampler {
+    in_rate: f64,
+    out_rate: f64,
+    step: f64, // Input samples per output sample
+    frac: f64, // Position of the next output sample, relative to the newest input
+    acc: [f32; 2],
+    acc_len: u32,
+    history: [[f32; 2]; TAPS],
+    head: usize,
+    kernel: Box<[f32]>, // PHASES rows of TAPS coefficients
+    high_pass: [(f32, f32); 2],
+    out: Vec<f32>, // Interleaved output samples
+}
+
+impl Resampler {
+    pub fn new(in_rate: u32, out_rate: u32) -> Self {
+        let in_rate = in_rate as f64 / DECIMATION as f64;
+        let out_rate = out_rate as f64;
+        let cutoff = 0.45 * in_rate.min(out_rate) / in_rate;
+
+        let mut kernel = vec![0.0; PHASES * TAPS].into_boxed_slice();
+        for (phase, row) in kernel.chunks_exact_mut(TAPS).enumerate() {
+            let frac = phase as f64 / PHASES as f64;
+            let mut sum = 0.0;
+            for (k, coef) in row.iter_mut().enumerate() {
+                let d = k as f64 - (TAPS / 2 - 1) as f64 - frac;
+                let x = 2.0 * cutoff * d;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                // Blackman window
+                let w = (d + TAPS as f64 / 2.0) / TAPS as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                *coef = (sinc * window) as f32;
+                sum += sinc * window;
+            }
+            row.iter_mut().for_each(|coef| *coef /= sum as f32);
+        }
+
+        Self {
+            in_rate,
+            out_rate,
+            step: in_rate / out_rate,
+            frac: 0.0,
+            acc: [0.0; 2],
+            acc_len: 0,
+            history: [[0.0; 2]; TAPS],
+            head: 0,
+            kernel,
+            high_pass: [(0.0, 0.0); 2],
+            out: Vec::with_capacity(out_rate as usize / 10),
+        }
+    }
+
+    // Stretches the output by `ratio`, values above 1.0 produce more samples
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.step = self.in_rate / (self.out_rate * ratio);
+    }
+
+    pub fn push(&mut self, (left, right): (f32, f32)) {
+        self.acc[0] += left;
+        self.acc[1] += right;
+        self.acc_len += 1;
+        if self.acc_len < DECIMATION {
+            return;
+        }
+
+        let n = DECIMATION as f32;
+        self.history[self.head] = [self.acc[0] / n, self.acc[1] / n];
+        self.head = (self.head + 1) % TAPS;
+        self.acc = [0.0; 2];
+        self.acc_len = 0;
+
+        while self.frac < 1.0 {
+            let phase = ((self.frac * PHASES as f64) as usize).min(PHASES - 1);
+            let row = &self.kernel[phase * TAPS..(phase + 1) * TAPS];
+            let mut sample = [0.0; 2];
+            for (k, coef) in row.iter().enumerate() {
+                let [l, r] = self.history[(self.head + k) % TAPS];
+                sample[0] += l * coef;
+                sample[1] += r * coef;
+            }
+            for (ch, s) in sample.into_iter().enumerate() {
+                let (prev_in, prev_out) = self.high_pass[ch];
+                let out = s - prev_in + HIGH_PASS * prev_out;
+                self.high_pass[ch] = (s, out);
+                self.out.push(out);
+            }
+            self.frac += self.step;
+        }
+        self.frac -= 1.0;
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.out
+    }
+
+    pub fn clear(&mut self) {
+        self.out.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{AUDIO_RATE, M_CYCLE_HZ};
+
+    // One second of a tone, or DC with `freq` 0, returns the left channel
+    fn resample(freq: f64, ratio: f64) -> Vec<f32> {
+        let mut resampler = Resampler::new(M_CYCLE_HZ, AUDIO_RATE);
+        resampler.set_ratio(ratio);
+        for i in 0..M_CYCLE_HZ {
+            let t = i as f64 / M_CYCLE_HZ as f64;
+            let v = 0.5 * (2.0 * PI * freq * t + PI / 2.0).sin() as f32;
+            resampler.push((v, -v));
+        }
+        let samples = resampler.samples();
+        // Both channels go through the same filters
+        for frame in samples.chunks_exact(2) {
+            assert!((frame[0] + frame[1]).abs() < 1e-5);
+        }
+        samples.iter().step_by(2).copied().collect()
+    }
+
+    #[test]
+    fn tone_passes_through() {
+        let out = resample(1000.0, 1.0);
+        assert!(out.len().abs_diff(AUDIO_RATE as usize) <= 1);
+        let peak = out[out.len() / 2..]
+            .iter()
+            .fold(0.0f32, |m, v| m.max(v.abs()));
+        assert!((peak - 0.5).abs() < 0.01, "peak {}", peak);
+    }
+
+    #[test]
+    fn dc_offset_is_removed() {
+        let out = resample(0.0, 1.0);
+        assert!(out[0] > 0.0);
+        assert!(out[out.len() - 1].abs() < 1e-3);
+    }
+
+    #[test]
+    fn ratio_stretches_the_output() {
+        let faster = resample(1000.0, 1.005).len() as f64;
+        assert!((faster / AUDIO_RATE as f64 - 1.005).abs() < 1e-4);
+        let slower = resample(1000.0, 0.995).len() as f64;
+        assert!((slower / AUDIO_RATE as f64 - 0.995).abs() < 1e-4);
+    }
+}
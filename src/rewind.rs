@@ -0,0 +1,239 @@
+// Snapshots of the system to step back through, kept within a memory budget
+//
+// Only the newest snapshot is kept whole. Each older one is stored XORed with
+// the snapshot after it and run-length encoded, so the bytes that didn't
+// change in between cost next to nothing. Stepping back decodes one snapshot
+// from the next, and the oldest are dropped once the budget is used up.
+use alloc::{collections::VecDeque, vec::Vec};
+
+pub struct Rewind {
+    interval: u32, // Frames between snapshots
+    budget: usize, // Bytes all snapshots may take
+    frames: u32,   // Frames since the last snapshot
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>, // Oldest first, each against the one after it
+    size: usize,
+    restored: bool, // `latest` is where the last step back landed
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            size: 0,
+            restored: false,
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    // Counts a finished frame, returns true when a snapshot is due
+    pub fn frame_done(&mut self) -> bool {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return false;
+        }
+        self.frames = 0;
+        true
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        self.restored = false;
+        if let Some(latest) = self.latest.take() {
+            let delta = encode(&latest, &state);
+            self.size += delta.len();
+            self.size -= latest.len();
+            self.deltas.push_back(delta);
+        }
+        self.size += state.len();
+        self.latest = Some(state);
+
+        while self.size > self.budget {
+            let Some(oldest) = self.deltas.pop_front() else {
+                break;
+            };
+            self.size -= oldest.len();
+        }
+    }
+
+    // Goes back to the newest snapshot, or to the one before it if that is
+    // where the last step landed. What was stepped to stays the newest, so the
+    // snapshots pushed after rewinding carry on from it. The oldest snapshot
+    // is stepped to again once there is nothing before it
+    pub fn step_back(&mut self) {
+        self.frames = 0;
+        if !self.restored {
+            self.restored = true;
+            return;
+        }
+        let (Some(latest), Some(delta)) = (&mut self.latest, self.deltas.pop_back()) else {
+            return;
+        };
+        let previous = decode(latest, &delta);
+        self.size += previous.len();
+        self.size -= latest.len() + delta.len();
+        *latest = previous;
+    }
+
+    pub fn latest(&self) -> Option<&[u8]> {
+        self.latest.as_deref()
+    }
+}
+
+// `older` XOR `newer`, with `newer` padded with zeros to the length of `older`:
+// the length of `older`, then pairs of runs, a count of bytes that are the
+// same in both and a count of bytes that differ followed by their XOR
+fn encode(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let len = older.len();
+    let xor = |i: usize| older[i] ^ newer.get(i).copied().unwrap_or(0);
+    let mut out = Vec::new();
+    put_varint(&mut out, len);
+    let mut i = 0;
+    while i < len {
+        let same = i;
+        while i < len && xor(i) == 0 {
+            i += 1;
+        }
+        let changed = i;
+        // A single unchanged byte costs less inside the run than ending it
+        while i < len && (xor(i) != 0 || (i + 1 < len && xor(i + 1) != 0)) {
+            i += 1;
+        }
+        put_varint(&mut out, changed - same);
+        put_varint(&mut out, i - changed);
+        out.extend((changed..i).map(xor));
+    }
+    out
+}
+
+fn decode(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let (len, mut pos) = varint(delta, 0);
+    let mut older: Vec<u8> = (0..len)
+        .map(|i| newer.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut i = 0;
+    while pos < delta.len() {
+        let (same, next) = varint(delta, pos);
+        let (changed, next) = varint(delta, next);
+        i += same;
+        for (b, x) in older[i..i + changed].iter_mut().zip(&delta[next..]) {
+            *b ^= x;
+        }
+        i += changed;
+        pos = next + changed;
+    }
+    older
+}
+
+// LEB128
+fn put_varint(out: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn varint(data: &[u8], mut pos: usize) -> (usize, usize) {
+    let mut v = 0;
+    let mut shift = 0;
+    loop {
+        let b = data[pos];
+        pos += 1;
+        v |= ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0 {
+            return (v, pos);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::iter;
+
+    fn round_trip(older: &[u8], newer: &[u8]) {
+        assert_eq!(decode(newer, &encode(older, newer)), older);
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        round_trip(&[], &[]);
+        round_trip(&[1, 2, 3], &[1, 2, 3]);
+        round_trip(&[1, 2, 3, 4], &[1, 0, 3, 0]);
+        // Unequal lengths
+        round_trip(&[5; 10], &[5; 4]);
+        round_trip(&[5; 4], &[5; 10]);
+        round_trip(&[], &[7; 3]);
+        round_trip(&[7; 3], &[]);
+    }
+
+    #[test]
+    fn codec_long_runs() {
+        let older: Vec<u8> = (0..100_000).map(|i| (i % 253) as u8).collect();
+        let mut newer = older.clone();
+        for b in &mut newer[40_000..40_300] {
+            *b ^= 0xa5;
+        }
+        newer[99_999] = !newer[99_999];
+        let delta = encode(&older, &newer);
+        // Runs longer than 127 bytes take multi-byte counts
+        assert!(delta.len() < 320);
+        assert_eq!(decode(&newer, &delta), older);
+
+        let changed = vec![0xff; 1000];
+        round_trip(&older, &changed);
+        round_trip(&changed, &older);
+    }
+
+    fn snapshot(n: u8) -> Vec<u8> {
+        vec![n; 64]
+    }
+
+    fn step_back(rewind: &mut Rewind) -> u8 {
+        rewind.step_back();
+        rewind.latest().unwrap()[0]
+    }
+
+    #[test]
+    fn steps_back_through_snapshots() {
+        let mut rewind = Rewind::new(1, 1 << 20);
+        for n in 0..5 {
+            rewind.push(snapshot(n));
+        }
+        assert_eq!(step_back(&mut rewind), 4);
+        assert_eq!(step_back(&mut rewind), 3);
+
+        // Carrying on from 3 keeps it in the history
+        rewind.push(snapshot(5));
+        assert_eq!(step_back(&mut rewind), 5);
+        assert_eq!(step_back(&mut rewind), 3);
+        assert_eq!(step_back(&mut rewind), 2);
+        assert_eq!(step_back(&mut rewind), 1);
+        assert_eq!(step_back(&mut rewind), 0);
+        assert_eq!(step_back(&mut rewind), 0);
+    }
+
+    #[test]
+    fn drops_the_oldest_snapshots_over_budget() {
+        let mut rewind = Rewind::new(1, 256);
+        for n in 0..20 {
+            rewind.push(snapshot(n));
+        }
+        assert!(rewind.size <= 256);
+        let reached: Vec<u8> = (0..20).map(|_| step_back(&mut rewind)).collect();
+        // The newest ones are kept and the oldest of those repeats
+        let oldest = reached[19];
+        assert!(oldest > 0);
+        let expected = (oldest..20).rev().chain(iter::repeat(oldest)).take(20);
+        assert!(reached.into_iter().eq(expected));
+    }
+}
@@ -0,0 +1,242 @@
+use crate::{
+    constants::{SERIAL_INT, SERIAL_INTERNAL_CLOCK, SERIAL_TRANSFER},
+    interrupts::Interrupts,
+    state::{Reader, Writer},
+};
+use alloc::{boxed::Box, collections::VecDeque, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+// Whatever is plugged into the link port
+pub trait SerialEndpoint {
+    // Called when a transfer driven by our internal clock completes, returns the received byte
+    fn transfer(&mut self, data: u8) -> u8;
+
+    // Called every M-cycle while waiting for an external clock,
+    // returns the received byte once the other side has clocked a transfer
+    fn poll(&mut self, _data: u8) -> Option<u8> {
+        None
+    }
+
+    // Called every M-cycle before anything else
+    fn tick(&mut self) {}
+
+    // Drains whatever the endpoint kept of the transmitted bytes
+    fn take_output(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+// Bytes kept by `Capture` until they are taken, older ones are dropped first
+const CAPTURE_LIMIT: usize = 64 * 1024;
+
+// Default endpoint, nothing is connected so 0xff is shifted in
+#[derive(Default)]
+pub struct Capture {
+    buffer: VecDeque<u8>,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SerialEndpoint for Capture {
+    fn transfer(&mut self, data: u8) -> u8 {
+        if self.buffer.len() == CAPTURE_LIMIT {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(data);
+        0xff
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        self.buffer.drain(..).collect()
+    }
+}
+
+// Prints transmitted bytes to stdout as they arrive
+#[cfg(feature = "std")]
+pub struct Stdout;
+
+#[cfg(feature = "std")]
+impl SerialEndpoint for Stdout {
+    fn transfer(&mut self, data: u8) -> u8 {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[data]);
+        let _ = stdout.flush();
+        0xff
+    }
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    out: u8,       // SB as latched when the transfer started
+    bits: u8,      // Bits left to shift in the current transfer
+    div_bit: bool, // DIV bit 0 as last seen by the internal clock
+    endpoint: Box<dyn SerialEndpoint>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            sb: 0,
+            sc: 0,
+            out: 0,
+            bits: 0,
+            div_bit: false,
+            endpoint: Box::new(Capture::new()),
+        }
+    }
+
+    pub fn set_endpoint(&mut self, endpoint: Box<dyn SerialEndpoint>) {
+        self.endpoint = endpoint;
+    }
+
+    // Bytes captured by the endpoint since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.endpoint.take_output()
+    }
+
+    // The endpoint is not part of the state, it stays connected
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u8(self.out);
+        w.u8(self.bits);
+        w.bool(self.div_bit);
+    }
+
+    pub(crate) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()? & (SERIAL_TRANSFER | SERIAL_INTERNAL_CLOCK);
+        self.out = r.u8()?;
+        self.bits = r.u8()?.min(8);
+        self.div_bit = r.bool()?;
+        Ok(())
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff01 => self.sb,
+            0xff02 => 0x7e | self.sc,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xff01 => self.sb = data,
+            0xff02 => {
+                self.sc = data & (SERIAL_TRANSFER | SERIAL_INTERNAL_CLOCK);
+                if self.sc & SERIAL_TRANSFER > 0 {
+                    self.out = self.sb;
+                    self.bits = 8;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn complete(&mut self, data: u8, interrupts: &mut Interrupts) {
+        self.sb = data;
+        self.bits = 0;
+        self.sc &= !SERIAL_TRANSFER;
+        interrupts.irq(SERIAL_INT);
+    }
+
+    // Advances the serial port by one M-cycle, `div` is the current value of the DIV register
+    pub fn emu(&mut self, div: u8, interrupts: &mut Interrupts) {
+        // The internal clock runs at 8192 Hz, one bit per falling edge of DIV bit 0
+        let div_bit = div & 0x01 > 0;
+        let falling_edge = self.div_bit && !div_bit;
+        self.div_bit = div_bit;
+
+        self.endpoint.tick();
+        if self.sc & SERIAL_TRANSFER == 0 {
+            return;
+        }
+
+        if self.sc & SERIAL_INTERNAL_CLOCK == 0 {
+            if let Some(data) = self.endpoint.poll(self.out) {
+                self.complete(data, interrupts);
+            }
+            return;
+        }
+
+        if !falling_edge {
+            return;
+        }
+        self.bits -= 1;
+        if self.bits > 0 {
+            // The byte being sent shifts out MSB first while the line idles high
+            self.sb = (self.sb << 1) | 1;
+            return;
+        }
+
+        // Hand the whole byte over once all 8 bits have been clocked out
+        let data = self.endpoint.transfer(self.out);
+        self.complete(data, interrupts);
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::Timer;
+
+    #[test]
+    fn captured_bytes_are_drained() {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::new();
+        for &data in b"ok" {
+            serial.write(0xff01, data);
+            serial.write(0xff02, SERIAL_TRANSFER | SERIAL_INTERNAL_CLOCK);
+            // Eight falling edges of DIV bit 0
+            for div in 0..=16 {
+                serial.emu(div, &mut interrupts);
+            }
+            assert_eq!(serial.read(0xff02) & SERIAL_TRANSFER, 0);
+        }
+        assert_eq!(serial.take_output(), b"ok");
+        assert!(serial.take_output().is_empty());
+    }
+
+    #[test]
+    fn internal_clock_interrupts_after_eight_bits() {
+        let mut serial = Serial::new();
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::new();
+        serial.write(0xff01, 0x42);
+        serial.write(0xff02, SERIAL_TRANSFER | SERIAL_INTERNAL_CLOCK);
+        // 8192 Hz is one bit every 128 M-cycles
+        for cycle in 1..=8 * 128 {
+            timer.emu(&mut interrupts);
+            serial.emu(timer.div(), &mut interrupts);
+            assert_eq!(interrupts.read(0xff0f) & SERIAL_INT > 0, cycle == 8 * 128);
+        }
+        assert_eq!(serial.read(0xff02) & SERIAL_TRANSFER, 0);
+        // Nothing is connected, so 0xff comes back
+        assert_eq!(serial.read(0xff01), 0xff);
+        assert_eq!(serial.take_output(), [0x42]);
+    }
+
+    #[test]
+    fn capture_keeps_the_newest_bytes() {
+        let mut capture = Capture::new();
+        for i in 0..CAPTURE_LIMIT + 3 {
+            capture.transfer(i as u8);
+        }
+        let output = capture.take_output();
+        assert_eq!(output.len(), CAPTURE_LIMIT);
+        assert_eq!(output[0], 3);
+    }
+}
@@ -0,0 +1,183 @@
+// Binary save state format
+//
+// A state starts with MAGIC, the version that wrote it and the oldest version
+// able to read it, followed by one chunk per component: a 4-byte tag, the
+// payload length as a 32-bit little-endian integer and the payload, ending
+// with an empty END chunk so other data can follow. Readers skip chunks they
+// don't know and ignore bytes past the fields they read, so later versions
+// can append fields and chunks without breaking older readers.
+use alloc::{format, string::String, vec::Vec};
+
+pub const MAGIC: [u8; 8] = *b"GEMUSTAT";
+pub const VERSION: u16 = 1;
+const END: &[u8; 4] = b"END ";
+// Bumped only for changes older versions can't read past
+const COMPATIBLE_VERSION: u16 = 1;
+
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        let mut writer = Self { buf: Vec::new() };
+        writer.bytes(&MAGIC);
+        writer.u16(VERSION);
+        writer.u16(COMPATIBLE_VERSION);
+        writer
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, v: i32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    // Length-prefixed bytes, for data whose size depends on the cartridge
+    pub fn blob(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.bytes(v);
+    }
+
+    pub fn chunk(&mut self, tag: &[u8; 4], f: impl FnOnce(&mut Self)) {
+        self.bytes(tag);
+        let start = self.buf.len();
+        self.u32(0);
+        f(self);
+        let len = (self.buf.len() - start - 4) as u32;
+        self.buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.chunk(END, |_| ());
+        self.buf
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    // Checks the header, returning a reader over the chunks
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        let mut reader = Self { data };
+        if reader.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err("not a gemu save state".into());
+        }
+        let version = reader.u16()?;
+        let compatible = reader.u16()?;
+        if compatible > VERSION {
+            return Err(format!(
+                "save state version {} needs version {} or later to load, this build is version {}",
+                version, compatible, VERSION
+            ));
+        }
+        Ok(reader)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("save state is truncated".into());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        buf.copy_from_slice(self.bytes(buf.len())?);
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let mut buf = [0; 2];
+        self.fill(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let mut buf = [0; 4];
+        self.fill(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, String> {
+        let mut buf = [0; 4];
+        self.fill(&mut buf)?;
+        Ok(i32::from_le_bytes(buf))
+    }
+
+    pub fn blob(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    // Fills `buf` from a blob, which has to be exactly as long
+    pub fn blob_into(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        let blob = self.blob()?;
+        if blob.len() != buf.len() {
+            return Err(format!(
+                "expected {} bytes of data, found {}",
+                buf.len(),
+                blob.len()
+            ));
+        }
+        buf.copy_from_slice(blob);
+        Ok(())
+    }
+
+    // The next chunk's tag and a reader over its payload, None after the last
+    pub fn chunk(&mut self) -> Result<Option<([u8; 4], Reader<'a>)>, String> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        let mut tag = [0; 4];
+        self.fill(&mut tag)?;
+        let len = self.u32()? as usize;
+        let data = self.bytes(len)?;
+        if &tag == END {
+            return Ok(None);
+        }
+        Ok(Some((tag, Reader { data })))
+    }
+}
+
+// FNV-1a, identifies the ROM a state was saved with
+pub fn hash(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
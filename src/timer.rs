@@ -0,0 +1,231 @@
+use crate::{
+    constants::{TIMER_ENABLE, TIMER_INT},
+    interrupts::Interrupts,
+    state::{Reader, Writer},
+};
+use alloc::string::String;
+
+#[derive(Clone, Default)]
+pub struct Timer {
+    counter: u16, // System counter, DIV is its upper 8 bits
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    overflow: bool,  // TIMA overflowed in the previous M-cycle and reads 0
+    reloading: bool, // TIMA is being reloaded from TMA in this M-cycle
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff04 => self.div(),
+            0xff05 => self.tima,
+            0xff06 => self.tma,
+            0xff07 => 0xf8 | self.tac,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xff04 => {
+                let old = self.signal();
+                self.counter = 0;
+                self.detect_edge(old);
+            }
+            0xff05 => {
+                // Writing during the reload cycle is ignored, writing before it cancels the reload
+                if !self.reloading {
+                    self.tima = data;
+                    self.overflow = false;
+                }
+            }
+            0xff06 => {
+                self.tma = data;
+                if self.reloading {
+                    self.tima = data;
+                }
+            }
+            0xff07 => {
+                let old = self.signal();
+                self.tac = data & 0x07;
+                self.detect_edge(old);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // The counter bit selected by TAC, ANDed with the timer enable bit
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & TIMER_ENABLE > 0 && (self.counter >> bit) & 1 > 0
+    }
+
+    fn detect_edge(&mut self, old: bool) {
+        if old && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            self.overflow |= overflow;
+        }
+    }
+
+    // Sets the registers from a BESS state, which only has the upper 8 bits
+    // of the system counter
+    pub(crate) fn load_bess(&mut self, div: u8, tima: u8, tma: u8, tac: u8) {
+        self.counter = (div as u16) << 8;
+        self.tima = tima;
+        self.tma = tma;
+        self.tac = tac & 0x07;
+        self.overflow = false;
+        self.reloading = false;
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.u16(self.counter);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.bool(self.overflow);
+        w.bool(self.reloading);
+    }
+
+    pub(crate) fn load(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.counter = r.u16()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()? & 0x07;
+        self.overflow = r.bool()?;
+        self.reloading = r.bool()?;
+        Ok(())
+    }
+
+    pub fn emu(&mut self, interrupts: &mut Interrupts) {
+        self.reloading = false;
+        if self.overflow {
+            self.overflow = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            interrupts.irq(TIMER_INT);
+        }
+
+        let old = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        self.detect_edge(old);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TIMA ticks on the falling edge of counter bit 3, every 4 M-cycles
+    fn timer(tima: u8, tma: u8) -> (Timer, Interrupts) {
+        let mut timer = Timer::new();
+        timer.write(0xff05, tima);
+        timer.write(0xff06, tma);
+        timer.write(0xff07, TIMER_ENABLE | 0b01);
+        (timer, Interrupts::new())
+    }
+
+    fn run(timer: &mut Timer, interrupts: &mut Interrupts, cycles: usize) {
+        for _ in 0..cycles {
+            timer.emu(interrupts);
+        }
+    }
+
+    fn timer_irq(interrupts: &Interrupts) -> bool {
+        interrupts.read(0xff0f) & TIMER_INT > 0
+    }
+
+    #[test]
+    fn counts_on_falling_edges() {
+        let (mut timer, mut interrupts) = timer(0, 0);
+        run(&mut timer, &mut interrupts, 3);
+        assert_eq!(timer.read(0xff05), 0);
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(0xff05), 1);
+        run(&mut timer, &mut interrupts, 8);
+        assert_eq!(timer.read(0xff05), 3);
+    }
+
+    #[test]
+    fn div_write_with_the_bit_set_ticks() {
+        let (mut timer, mut interrupts) = timer(0, 0);
+        run(&mut timer, &mut interrupts, 2);
+        timer.write(0xff04, 0);
+        assert_eq!(timer.read(0xff05), 1);
+
+        // With the bit clear, resetting DIV doesn't tick
+        run(&mut timer, &mut interrupts, 1);
+        timer.write(0xff04, 0);
+        assert_eq!(timer.read(0xff05), 1);
+    }
+
+    #[test]
+    fn tac_write_with_the_bit_set_ticks() {
+        let (mut timer, mut interrupts) = timer(0, 0);
+        run(&mut timer, &mut interrupts, 2);
+        // Disabling the timer
+        timer.write(0xff07, 0b01);
+        assert_eq!(timer.read(0xff05), 1);
+
+        // Switching to a counter bit that is clear
+        timer.write(0xff07, TIMER_ENABLE | 0b01);
+        timer.write(0xff07, TIMER_ENABLE | 0b10);
+        assert_eq!(timer.read(0xff05), 2);
+    }
+
+    #[test]
+    fn reloads_one_cycle_after_overflow() {
+        let (mut timer, mut interrupts) = timer(0xff, 0x42);
+        run(&mut timer, &mut interrupts, 4);
+        assert_eq!(timer.read(0xff05), 0);
+        assert!(!timer_irq(&interrupts));
+
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(0xff05), 0x42);
+        assert!(timer_irq(&interrupts));
+    }
+
+    #[test]
+    fn tima_write_before_reload_cancels_it() {
+        let (mut timer, mut interrupts) = timer(0xff, 0x42);
+        run(&mut timer, &mut interrupts, 4);
+        timer.write(0xff05, 0x10);
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(0xff05), 0x10);
+        assert!(!timer_irq(&interrupts));
+    }
+
+    #[test]
+    fn writes_during_reload() {
+        let (mut timer, mut interrupts) = timer(0xff, 0x42);
+        run(&mut timer, &mut interrupts, 5);
+
+        // TIMA writes are ignored, TMA writes also go to TIMA
+        timer.write(0xff05, 0x10);
+        assert_eq!(timer.read(0xff05), 0x42);
+        timer.write(0xff06, 0x55);
+        assert_eq!(timer.read(0xff05), 0x55);
+
+        // Both behave normally once the reload is over
+        run(&mut timer, &mut interrupts, 1);
+        timer.write(0xff06, 0x66);
+        assert_eq!(timer.read(0xff05), 0x55);
+        timer.write(0xff05, 0x10);
+        assert_eq!(timer.read(0xff05), 0x10);
+    }
+}
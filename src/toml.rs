@@ -0,0 +1,311 @@
+// The subset of TOML the config file needs: tables, and keys set to strings,
+// integers, floats or booleans
+use std::ops::RangeInclusive;
+
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+impl Value {
+    pub fn string(self) -> Result<String, String> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err("expected a string".to_string()),
+        }
+    }
+
+    pub fn integer(self, range: RangeInclusive<i64>) -> Result<i64, String> {
+        match self {
+            Value::Integer(v) if range.contains(&v) => Ok(v),
+            Value::Integer(v) => Err(format!(
+                "{} is out of range ({}-{})",
+                v,
+                range.start(),
+                range.end()
+            )),
+            _ => Err("expected an integer".to_string()),
+        }
+    }
+
+    // An integer or a float within `range`
+    pub fn number(self, range: RangeInclusive<f64>) -> Result<f64, String> {
+        let v = match self {
+            Value::Integer(v) => v as f64,
+            Value::Float(v) => v,
+            _ => return Err("expected a number".to_string()),
+        };
+        if range.contains(&v) {
+            Ok(v)
+        } else {
+            Err(format!(
+                "{} is out of range ({}-{})",
+                v,
+                range.start(),
+                range.end()
+            ))
+        }
+    }
+
+    pub fn boolean(self) -> Result<bool, String> {
+        match self {
+            Value::Boolean(v) => Ok(v),
+            _ => Err("expected true or false".to_string()),
+        }
+    }
+}
+
+// A `key = value` line, `table` is empty before the first table header
+pub struct Entry {
+    pub line: usize,
+    pub table: String,
+    pub key: String,
+    pub value: Value,
+}
+
+impl Entry {
+    // The key as `table.key`, for error messages
+    pub fn name(&self) -> String {
+        full_name(&self.table, &self.key)
+    }
+}
+
+fn full_name(table: &str, key: &str) -> String {
+    if table.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", table, key)
+    }
+}
+
+// Returns the entries in file order, tables other than `tables` are rejected.
+// Errors start with the line number and name the offending key
+pub fn parse(s: &str, tables: &[&str]) -> Result<Vec<Entry>, String> {
+    let mut entries = vec![];
+    let mut table = String::new();
+
+    for (i, line) in s.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = strip_comment(name)
+                .strip_suffix(']')
+                .ok_or_else(|| format!("{}: unterminated table header", line_no))?;
+            table = name.trim().to_string();
+            if !tables.contains(&table.as_str()) {
+                return Err(format!("{}: unknown table `{}`", line_no, table));
+            }
+            continue;
+        }
+
+        let (key, rest) = parse_key(line).map_err(|e| format!("{}: {}", line_no, e))?;
+        let value = parse_value(rest)
+            .map_err(|e| format!("{}: `{}`: {}", line_no, full_name(&table, &key), e))?;
+        entries.push(Entry {
+            line: line_no,
+            table: table.clone(),
+            key,
+            value,
+        });
+    }
+    Ok(entries)
+}
+
+// Splits `key = value` into the key, bare or quoted, and the rest after `=`
+fn parse_key(line: &str) -> Result<(String, &str), String> {
+    let (key, rest) = if line.starts_with('"') {
+        parse_string(line)?
+    } else {
+        let end = line
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(line.len());
+        if end == 0 {
+            return Err(format!("expected a key, found `{}`", line));
+        }
+        (line[..end].to_string(), &line[end..])
+    };
+    let rest = rest
+        .trim_start()
+        .strip_prefix('=')
+        .ok_or_else(|| format!("`{}`: expected `=` after the key", key))?;
+    Ok((key, rest))
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    let s = s.trim_start();
+    let (value, rest) = if s.starts_with('"') {
+        let (value, rest) = parse_string(s)?;
+        (Value::String(value), rest)
+    } else {
+        let end = s
+            .find(|c: char| c.is_whitespace() || c == '#')
+            .unwrap_or(s.len());
+        let value = match &s[..end] {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            v if v.contains('.') => Value::Float(
+                v.replace('_', "")
+                    .parse()
+                    .map_err(|_| format!("invalid value `{}`", v))?,
+            ),
+            v => Value::Integer(
+                v.replace('_', "")
+                    .parse()
+                    .map_err(|_| format!("invalid value `{}`", v))?,
+            ),
+        };
+        (value, &s[end..])
+    };
+    if !strip_comment(rest).is_empty() {
+        return Err(format!("unexpected `{}` after the value", rest.trim()));
+    }
+    Ok(value)
+}
+
+// Parses a basic string at the start of `s`, returning it and the rest of `s`
+fn parse_string(s: &str) -> Result<(String, &str), String> {
+    let mut value = String::new();
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &s[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '"')) => value.push('"'),
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                _ => return Err("invalid escape in string".to_string()),
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+fn strip_comment(s: &str) -> &str {
+    s.split_once('#').map_or(s, |(s, _)| s).trim()
+}
+
+// A basic string that parses back to `s`
+pub fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLES: [&str; 2] = ["paths", "keys"];
+
+    fn error(s: &str) -> String {
+        match parse(s, &TABLES) {
+            Ok(_) => panic!("{:?} was accepted", s),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn parses_entries() {
+        let s = r#"# comment
+scale = 3
+fullscreen = true
+fast_forward = 2.5
+deadzone = 8_000 # trailing comment
+
+[paths]
+captures = "caps # not a comment"
+saves = "a \"quoted\" dir\\x"
+
+[ keys ] # comment
+"Left Shift" = "select"
+"#;
+        let entries = parse(s, &TABLES).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| (e.line, e.name())).collect();
+        assert_eq!(
+            names,
+            [
+                (2, "scale".to_string()),
+                (3, "fullscreen".to_string()),
+                (4, "fast_forward".to_string()),
+                (5, "deadzone".to_string()),
+                (8, "paths.captures".to_string()),
+                (9, "paths.saves".to_string()),
+                (12, "keys.Left Shift".to_string()),
+            ]
+        );
+
+        let mut values = entries.into_iter().map(|e| e.value);
+        let mut next = || values.next().unwrap();
+        assert_eq!(next().integer(1..=16), Ok(3));
+        assert_eq!(next().boolean(), Ok(true));
+        assert_eq!(next().number(0.0..=64.0), Ok(2.5));
+        assert_eq!(next().integer(0..=i16::MAX as i64), Ok(8000));
+        assert_eq!(next().string().unwrap(), "caps # not a comment");
+        assert_eq!(next().string().unwrap(), "a \"quoted\" dir\\x");
+        assert_eq!(next().string().unwrap(), "select");
+    }
+
+    #[test]
+    fn quoted_strings_parse_back() {
+        for s in ["plain", "a \"quoted\" dir\\x", "# not a comment"] {
+            let entries = parse(&format!("key = {}", quote(s)), &[]).unwrap();
+            assert_eq!(entries[0].key, "key");
+            let value = entries.into_iter().next().unwrap().value;
+            assert_eq!(value.string().unwrap(), s);
+        }
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_line() {
+        for (s, e) in [
+            ("[keys\n", "1: unterminated table header"),
+            ("scale = 2\n[video]\n", "2: unknown table `video`"),
+            ("= 2\n", "1: expected a key, found `= 2`"),
+            ("scale 2\n", "1: `scale`: expected `=` after the key"),
+            ("scale = big\n", "1: `scale`: invalid value `big`"),
+            (
+                "scale = 2 3\n",
+                "1: `scale`: unexpected `3` after the value",
+            ),
+            (
+                "# comment\n\n[paths]\nsaves = \"x\n",
+                "4: `paths.saves`: unterminated string",
+            ),
+            (
+                "palette = \"\\q\"\n",
+                "1: `palette`: invalid escape in string",
+            ),
+        ] {
+            assert_eq!(error(s), e);
+        }
+    }
+
+    #[test]
+    fn checks_value_types_and_ranges() {
+        let value = |s: &str| parse(&format!("key = {}", s), &[]).unwrap().remove(0).value;
+        assert_eq!(
+            value("\"2\"").integer(1..=16),
+            Err("expected an integer".into())
+        );
+        assert_eq!(
+            value("17").integer(1..=16),
+            Err("17 is out of range (1-16)".into())
+        );
+        assert_eq!(
+            value("2.5").number(0.05..=1.0),
+            Err("2.5 is out of range (0.05-1)".into())
+        );
+        assert_eq!(value("3").number(0.0..=4.0), Ok(3.0));
+        assert_eq!(
+            value("true").number(0.0..=4.0),
+            Err("expected a number".into())
+        );
+        assert_eq!(value("1").boolean(), Err("expected true or false".into()));
+        assert_eq!(value("1").string(), Err("expected a string".into()));
+    }
+}
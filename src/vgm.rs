@@ -0,0 +1,177 @@
+use crate::constants::M_CYCLE_HZ;
+use alloc::{vec, vec::Vec};
+
+const VGM_RATE: u64 = 44100;
+const DATA_OFFSET: usize = 0x100;
+const DMG_CLOCK: u32 = 4194304;
+
+// Logs APU register writes as a VGM 1.61 command stream
+pub struct VgmLogger {
+    data: Vec<u8>,
+    cycles: u64,  // M-cycles since logging started
+    samples: u64, // Samples covered by the waits written so far
+    loop_point: Option<(usize, u64)>,
+}
+
+impl VgmLogger {
+    pub fn new() -> Self {
+        Self {
+            data: vec![],
+            cycles: 0,
+            samples: 0,
+            loop_point: None,
+        }
+    }
+
+    pub fn emu(&mut self) {
+        self.cycles += 1;
+    }
+
+    fn sync(&mut self) {
+        let now = self.cycles * VGM_RATE / M_CYCLE_HZ as u64;
+        let mut wait = now - self.samples;
+        self.samples = now;
+
+        while wait > 0 {
+            match wait {
+                735 => self.data.push(0x62),
+                882 => self.data.push(0x63),
+                1..=16 => self.data.push(0x70 + wait as u8 - 1),
+                _ => {
+                    let n = wait.min(0xffff) as u16;
+                    self.data.push(0x61);
+                    self.data.extend_from_slice(&n.to_le_bytes());
+                    wait -= n as u64;
+                    continue;
+                }
+            }
+            wait = 0;
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        self.sync();
+        self.data
+            .extend_from_slice(&[0xb3, (addr - 0xff10) as u8, data]);
+    }
+
+    // Playback loops back to the current position once the log ends
+    pub fn mark_loop(&mut self) {
+        self.sync();
+        self.loop_point = Some((self.data.len(), self.samples));
+    }
+
+    fn gd3(title: &str) -> Vec<u8> {
+        let fields = [
+            "",
+            "",
+            title,
+            "",
+            "Nintendo Game Boy",
+            "",
+            "",
+            "",
+            "",
+            "gemu",
+            "",
+        ];
+        let mut strings = vec![];
+        for field in fields {
+            for c in field.encode_utf16().chain([0]) {
+                strings.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        let mut gd3 = vec![];
+        gd3.extend_from_slice(b"Gd3 ");
+        gd3.extend_from_slice(&0x100u32.to_le_bytes());
+        gd3.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        gd3.extend_from_slice(&strings);
+        gd3
+    }
+
+    // Builds the VGM file from the log so far
+    pub fn finish(&mut self, title: &str) -> Vec<u8> {
+        self.sync();
+
+        let mut file = vec![0; DATA_OFFSET];
+        file.extend_from_slice(&self.data);
+        file.push(0x66); // End of sound data
+        let gd3_offset = file.len();
+        file.extend_from_slice(&Self::gd3(title));
+
+        let mut put =
+            |offset: usize, v: u32| file[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+        put(0x08, 0x161);
+        put(0x14, (gd3_offset - 0x14) as u32);
+        put(0x18, self.samples as u32);
+        if let Some((offset, samples)) = self.loop_point {
+            put(0x1c, (DATA_OFFSET + offset - 0x1c) as u32);
+            put(0x20, (self.samples - samples) as u32);
+        }
+        put(0x34, (DATA_OFFSET - 0x34) as u32);
+        put(0x80, DMG_CLOCK);
+        file[0..4].copy_from_slice(b"Vgm ");
+        let eof = file.len() as u32 - 4;
+        file[4..8].copy_from_slice(&eof.to_le_bytes());
+
+        file
+    }
+}
+
+impl Default for VgmLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(file: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn header_and_commands() {
+        let mut vgm = VgmLogger::new();
+        vgm.write(0xff26, 0x80);
+        // 735 samples, a 60th of a second
+        for _ in 0..17477 {
+            vgm.emu();
+        }
+        vgm.mark_loop();
+        vgm.write(0xff12, 0xf0);
+        for _ in 0..100 {
+            vgm.emu();
+        }
+        let file = vgm.finish("Title");
+
+        assert_eq!(&file[0..4], b"Vgm ");
+        assert_eq!(u32_at(&file, 0x04), file.len() - 0x04);
+        assert_eq!(u32_at(&file, 0x08), 0x161);
+        assert_eq!(u32_at(&file, 0x18), 739);
+        assert_eq!(u32_at(&file, 0x20), 4);
+        assert_eq!(u32_at(&file, 0x80), 4194304);
+
+        let data = 0x34 + u32_at(&file, 0x34);
+        assert_eq!(data, 0x100);
+        let commands = [0xb3, 0x16, 0x80, 0x62, 0xb3, 0x02, 0xf0, 0x73, 0x66];
+        assert_eq!(file[data..data + commands.len()], commands);
+        // The loop starts at the write after the 735 sample wait
+        assert_eq!(0x1c + u32_at(&file, 0x1c), data + 4);
+
+        let gd3 = 0x14 + u32_at(&file, 0x14);
+        assert_eq!(gd3, data + commands.len());
+        assert_eq!(&file[gd3..gd3 + 4], b"Gd3 ");
+        assert_eq!(u32_at(&file, gd3 + 4), 0x100);
+        let strings: Vec<u16> = file[gd3 + 12..]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        assert_eq!(strings.len() * 2, u32_at(&file, gd3 + 8));
+        let fields: Vec<_> = strings.split(|&c| c == 0).collect();
+        assert_eq!(fields[2], "Title".encode_utf16().collect::<Vec<_>>());
+    }
+}
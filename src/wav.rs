@@ -0,0 +1,68 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+// 16-bit PCM WAV file, written incrementally and finalized on drop
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?; // Patched once the length is known
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { file, data_len: 0 })
+    }
+
+    // Writes interleaved samples in -1.0..=1.0, failing once the file would
+    // outgrow the 4 GiB the RIFF header can describe
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let data_len = u32::try_from(samples.len() * 2)
+            .ok()
+            .and_then(|len| self.data_len.checked_add(len))
+            .filter(|&len| len <= u32::MAX - 36)
+            .ok_or_else(|| io::Error::other("WAV file size limit reached"))?;
+        for &s in samples {
+            let v = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&v.to_le_bytes())?;
+        }
+        self.data_len = data_len;
+        Ok(())
+    }
+
+    // Patches the chunk lengths in the header, leaving the file position at the end
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("failed to finalize WAV file: {}", e);
+        }
+    }
+}